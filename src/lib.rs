@@ -0,0 +1,4 @@
+//! The parts of the Gemini REPL that work without a terminal session or the
+//! network, shared with the `gemini-repl` binary.
+
+pub mod render;
@@ -1,412 +1,6611 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::env;
-use std::io::{self, Write};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 
 use bat::PrettyPrinter;
-use pulldown_cmark::{CodeBlockKind, Event, Parser, Tag, Options as ParserOptions, HeadingLevel, TagEnd};
+use chrono::{DateTime, Local};
+use clap::Parser as CliParser;
+use gemini_repl::render::{
+    glyphs, language_extension, mark_spans, render_markdown, theme, trim_leading_blank_lines,
+    truncate_display, underline_marks, validate_theme, RenderMode, RenderOptions, ASCII_ENABLED,
+    COLOR_ENABLED,
+};
+use pulldown_cmark::{CodeBlockKind, Event, Options as ParserOptions, Parser, Tag, TagEnd};
+use regex::Regex;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use reqwest::Client;
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Cmd, Editor, KeyEvent, Movement};
 use serde::{Deserialize, Serialize};
-use term_size;
-use textwrap::{wrap, Options};
-
-// ANSI color codes for formatting
-const RESET: &str = "\x1b[0m";
-const BOLD: &str = "\x1b[1m";
-const DIM: &str = "\x1b[2m";
-const ITALIC: &str = "\x1b[3m";
-const HEADING_COLOR: &str = "\x1b[38;5;40m"; // A vibrant green
-const BLUE: &str = "\x1b[34m";
-const KEYWORD_COLOR: &str = "\x1b[38;5;111m"; // A distinct blue/cyan
-const YELLOW: &str = "\x1b[33m";
-const RED: &str = "\x1b[31m";
-const MAGENTA: &str = "\x1b[35m";
-const STRIKETHROUGH: &str = "\x1b[9m";
-const LIST_ITEM_BULLET: &str = "▸";
-
-/// Renders markdown text to the terminal with ANSI colors and formatting.
-fn render_markdown(text: &str) {
-    let (cols, _rows) = term_size::dimensions().unwrap_or((80, 24));
-    let wrap_width = (cols * 3 / 4).min(100); // Better width calculation
-    let wrap_options = Options::new(wrap_width)
-        .word_separator(textwrap::WordSeparator::AsciiSpace)
-        .break_words(false);
-
-    let parser = Parser::new_ext(text, ParserOptions::all()); // Enable all markdown extensions
-    let mut code_buffer = String::new();
-    let mut code_language = String::from("text");
-    let mut in_code_block = false;
-    let mut list_stack: Vec<(Option<u64>, usize)> = Vec::new(); // (start_num, indent_level)
-    let mut link_stack: Vec<String> = Vec::new();
-    let mut pending_newlines = 0;
-    let mut at_line_start = true;
-    let mut last_was_list_item = false;
-    
-
-    for event in parser {
-        match event {
-            Event::Start(tag) => match tag {
-                Tag::Paragraph => {
-                    if !at_line_start && !last_was_list_item {
-                        pending_newlines = pending_newlines.max(1);
-                    }
-                    
-                }
-                Tag::Heading { level, .. } => {
-                    flush_newlines(&mut pending_newlines, 2);
-                    let header_prefix = match level {
-                        HeadingLevel::H1 => "# ",
-                        HeadingLevel::H2 => "## ",
-                        HeadingLevel::H3 => "### ",
-                        HeadingLevel::H4 => "#### ",
-                        HeadingLevel::H5 => "##### ",
-                        HeadingLevel::H6 => "###### ",
-                    };
-                    print!("{}{}{}", BOLD, HEADING_COLOR, header_prefix);
-                    at_line_start = false;
-                }
-                Tag::BlockQuote(_) => {
-                    flush_newlines(&mut pending_newlines, 1);
-                    at_line_start = true;
-                }
-                Tag::CodeBlock(kind) => {
-                    flush_newlines(&mut pending_newlines, 1);
-                    in_code_block = true;
-                    code_language = match kind {
-                        CodeBlockKind::Fenced(lang) => {
-                            let lang_str = lang.to_string();
-                            if lang_str.is_empty() {
-                                "txt".to_string()
-                            } else {
-                                lang_str
-                            }
-                        }
-                        CodeBlockKind::Indented => "txt".to_string(),
-                    };
-                }
-                Tag::List(start_num) => {
-                    if !list_stack.is_empty() {
-                        pending_newlines = pending_newlines.max(1);
-                    } else {
-                        flush_newlines(&mut pending_newlines, 1);
-                    }
-                    let indent_level = list_stack.len();
-                    list_stack.push((start_num, indent_level));
-                }
-                Tag::Item => {
-                    if !at_line_start {
-                        print!("\n");
-                    }
+use similar::{ChangeTag, TextDiff};
+use unicode_width::UnicodeWidthStr;
 
-                    let current_level = list_stack.len().saturating_sub(1);
-                    let indent = "  ".repeat(current_level);
+/// Whether the locale rules out UTF-8 output, e.g. `LANG=C` or an ISO-8859
+/// locale. An unset locale says nothing either way.
+fn locale_is_limited() -> bool {
+    let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.is_empty());
+    match locale {
+        Some(locale) => {
+            let locale = locale.to_ascii_lowercase();
+            !locale.contains("utf-8") && !locale.contains("utf8")
+        }
+        None => false,
+    }
+}
 
-                    if let Some((Some(num), _)) = list_stack.last_mut() {
-                        print!("{}{}{:2}. {}", indent, MAGENTA, num, RESET);
-                        *num += 1;
-                    } else {
-                        print!("{}{} {} {}", indent, MAGENTA, LIST_ITEM_BULLET, RESET);
-                    }
-                    at_line_start = false;
-                    last_was_list_item = true;
-                    pending_newlines = 0;
-                }
-                Tag::Emphasis => print!("{}", ITALIC),
-                Tag::Strong => print!("{}{}", BOLD, YELLOW),
-                Tag::Strikethrough => print!("{}", STRIKETHROUGH),
-                Tag::Link { dest_url, .. } => {
-                    link_stack.push(dest_url.to_string());
-                    print!("{}[", BLUE);
-                }
-                _ => {}
-            },
-            Event::End(tag) => match tag {
-                
-                TagEnd::Heading(_) => {
-                    print!("{}", RESET);
-                    pending_newlines = pending_newlines.max(2);
-                    at_line_start = true;
-                }
-                TagEnd::BlockQuote => {
-                    pending_newlines = pending_newlines.max(1);
-                    at_line_start = true;
-                }
-                TagEnd::CodeBlock => {
-                    in_code_block = false;
-                    render_code_block(&code_buffer, &code_language);
-                    code_buffer.clear();
-                    code_language = String::from("text");
-                    pending_newlines = pending_newlines.max(1);
-                    at_line_start = true;
-                }
-                TagEnd::List(_) => {
-                    list_stack.pop();
-                    if list_stack.is_empty() {
-                        pending_newlines = pending_newlines.max(1);
-                        last_was_list_item = false;
-                    }
-                    at_line_start = true;
-                }
-                TagEnd::Item => {
-                    // Don't add extra newlines here, handled by next item or list end
-                }
-                TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough => {
-                    print!("{}", RESET);
-                }
-                TagEnd::Link => {
-                    if let Some(url) = link_stack.pop() {
-                        print!("]({}{}{})", BLUE, url, RESET);
-                    } else {
-                        print!("]");
-                    }
-                }
-                _ => {}
+/// Whether the terminal says it can't show colors: `TERM=dumb`, or a terminfo
+/// entry with fewer than 8 of them, like `vt100`. Terminals that name color
+/// support in `TERM` or `COLORTERM` skip the terminfo lookup, and anything
+/// that can't be looked up is assumed to have colors.
+fn terminal_lacks_color() -> bool {
+    let Ok(term) = env::var("TERM") else {
+        return false;
+    };
+    if term == "dumb" {
+        return true;
+    }
+    if env::var_os("COLORTERM").is_some() || term.contains("color") || term.contains("256") {
+        return false;
+    }
+    let Ok(output) = Command::new("tput")
+        .args(["-T", &term, "colors"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+    else {
+        return false;
+    };
+    let colors = String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse::<i32>();
+    output.status.success() && colors.is_ok_and(|colors| colors < 8)
+}
+
+const DEFAULT_THINKING_MESSAGE: &str = "Thinking...";
+/// Seconds after which the thinking indicator changes, and what it changes to.
+const DEFAULT_SLOW_MESSAGES: &[(u64, &str)] = &[
+    (10, "Still working, this model can be slow..."),
+    (
+        30,
+        "Still waiting, long prompts can take a minute or more...",
+    ),
+];
+const SPINNER_INTERVAL: Duration = Duration::from_millis(100);
+/// Columns given to one-line previews in `sessions` and `history`.
+const PREVIEW_WIDTH: usize = 60;
+
+const API_KEY_ENV: &str = "GEMINI_API_KEY";
+/// Environment variable with the model to use when `--model` isn't given.
+const MODEL_ENV: &str = "GEMINI_MODEL";
+const DEFAULT_API_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+
+/// Set once from `--base-url` at startup.
+static API_BASE_URL: OnceLock<String> = OnceLock::new();
+
+/// Where the API key came from, for the message about an invalid key: the
+/// environment variable or `api_key` in the config file. Set once at startup.
+static API_KEY_SOURCE: OnceLock<String> = OnceLock::new();
+
+fn api_base_url() -> &'static str {
+    API_BASE_URL
+        .get()
+        .map_or(DEFAULT_API_BASE_URL, String::as_str)
+}
+
+/// Checks a `--base-url` before any request is sent. The API key travels in
+/// every URL, so plain `http://` is refused unless `allow_http` is set.
+fn resolve_base_url(base_url: &str, allow_http: bool) -> Result<String, String> {
+    let url = reqwest::Url::parse(base_url)
+        .map_err(|e| format!("--base-url {} isn't a valid URL: {}", base_url, e))?;
+    match url.scheme() {
+        "https" => {}
+        "http" if allow_http => {}
+        "http" => {
+            return Err(format!(
+                "--base-url {} uses plain HTTP, which would send your API key unencrypted. Use https://, or pass --allow-http to test against a local server.",
+                base_url
+            ))
+        }
+        scheme => {
+            return Err(format!(
+                "--base-url {} must be an https:// URL, not {}://",
+                base_url, scheme
+            ))
+        }
+    }
+    Ok(base_url.trim_end_matches('/').to_string())
+}
+const DEFAULT_MODEL: &str = "gemini-2.5-flash-lite-preview-06-17";
+
+/// Short names that stand for a model wherever one is named; `[aliases]` in
+/// the config file adds to them or overrides them.
+const BUILT_IN_ALIASES: &[(&str, &str)] = &[
+    ("pro", "gemini-2.5-pro"),
+    ("flash", "gemini-2.5-flash"),
+    ("lite", "gemini-2.5-flash-lite"),
+];
+
+/// Prefix that sends a single prompt to another model, e.g. `@model:gemini-2.5-pro ...`.
+const MODEL_OVERRIDE_PREFIX: &str = "@model:";
+
+/// Prefix that includes a file in the prompt, e.g. `@file:notes.md summarize this`.
+const FILE_DIRECTIVE: &str = "@file:";
+
+/// Cap on the total size of the files `@file:` directives add to one prompt.
+const MAX_FILE_BYTES: usize = 512 * 1024;
+
+/// Size of each request in a Files API upload. The resumable protocol needs a
+/// multiple of 256 KiB.
+const UPLOAD_CHUNK_BYTES: usize = 8 * 1024 * 1024;
+
+/// How long a `cache-context` cache lives on the server.
+const CACHE_TTL: &str = "3600s";
+
+/// Marks the user turn that `summarize` puts in place of older turns.
+const SUMMARY_PREFIX: &str = "[Summary of the earlier conversation]\n";
+/// The model turn that follows the summary, so user and model turns still alternate.
+const SUMMARY_ACKNOWLEDGEMENT: &str = "Understood, I'll keep that summary in mind.";
+/// Stands in for the text of a `secret` prompt in saved sessions and `history`.
+const REDACTED_TEXT: &str = "[redacted]";
+/// How many recent turns `summarize` keeps verbatim unless told otherwise.
+const SUMMARIZE_KEEP_TURNS: usize = 4;
+const SUMMARIZE_PROMPT: &str = "Summarize the conversation so far in a few short paragraphs. \
+Keep every fact, decision, name and piece of code that later questions may depend on. \
+Reply with the summary only.";
+
+/// Network retries for a failed request unless `--retries` says otherwise.
+const DEFAULT_NETWORK_RETRIES: u32 = 2;
+/// Wait before the first network retry, doubled for each one after it.
+const NETWORK_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Longest wait a rate limit may ask for before a retry; asking for longer
+/// fails the request instead of leaving the prompt hanging.
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+/// Pause before each turn in `replay` unless another is given.
+const REPLAY_DELAY: Duration = Duration::from_secs(1);
+
+/// The prompt `bench` sends, long enough to measure output throughput.
+const BENCH_PROMPT: &str = "Write a short paragraph about the history of the printing press.";
+
+/// The `generationConfig` fields this client knows, as accepted by `--gen-config`.
+const GENERATION_CONFIG_FIELDS: &[&str] = &[
+    "temperature",
+    "topK",
+    "seed",
+    "thinkingConfig",
+    "candidateCount",
+    "presencePenalty",
+    "frequencyPenalty",
+];
+
+/// How far ahead in the text a token is looked for before it is taken for
+/// one that isn't there.
+const TOKEN_SEARCH_WINDOW: usize = 32;
+/// Tokens the model chose with less than this probability are underlined
+/// when `logprobs` is on.
+const LOW_CONFIDENCE_PROBABILITY: f64 = 0.5;
+
+/// The most candidates the API returns for one request.
+const MAX_CANDIDATE_COUNT: u32 = 8;
+
+/// Keys of `Config`, and of the tables within it, as written in the config file.
+const CONFIG_KEYS: &[&str] = &[
+    "model",
+    "edit_mode",
+    "theme",
+    "thinking",
+    "thinking_message",
+    "slow_messages",
+    "models",
+    "commands",
+    "history_size",
+    "history_dedup",
+    "retry_jitter",
+    "api_key",
+    "api_client",
+    "prompt_wrapper",
+    "aliases",
+    "pool_max_idle_per_host",
+];
+const MODEL_CONFIG_KEYS: &[&str] = &["temperature", "top_k", "seed", "thinking_budget"];
+const CUSTOM_COMMAND_KEYS: &[&str] = &["run", "context"];
+const SLOW_MESSAGE_KEYS: &[&str] = &["after", "message"];
+
+/// Prompts kept in the history file unless `history_size` says otherwise.
+const DEFAULT_HISTORY_SIZE: usize = 1000;
+
+/// Per-project instructions, looked for in the current directory and then
+/// in each parent.
+const PROJECT_CONTEXT_FILE: &str = ".gemini.md";
+
+/// Stands for the prompt in a `prompt_wrapper` template.
+const PROMPT_PLACEHOLDER: &str = "{prompt}";
+
+/// Identifies the client to API gateways, which may log or route on it.
+const API_CLIENT_HEADER: &str = "x-goog-api-client";
+/// Sent in `API_CLIENT_HEADER` unless `api_client` in the config file says otherwise.
+const DEFAULT_API_CLIENT: &str = concat!("gemini_cli/", env!("CARGO_PKG_VERSION"));
+
+/// Headers the client sets itself, which `--header` may not override.
+const PROTECTED_HEADERS: &[&str] = &[
+    "content-type",
+    "content-length",
+    "host",
+    "transfer-encoding",
+    "x-goog-api-key",
+];
+
+/// Approximate input prices in USD per million tokens, keyed by model name
+/// prefix. More specific prefixes must come first.
+const INPUT_PRICING: &[(&str, f64)] = &[
+    ("gemini-2.5-flash-lite", 0.10),
+    ("gemini-2.5-flash", 0.30),
+    ("gemini-2.5-pro", 1.25),
+    ("gemini-2.0-flash-lite", 0.075),
+    ("gemini-2.0-flash", 0.10),
+    ("gemini-1.5-flash", 0.075),
+    ("gemini-1.5-pro", 1.25),
+];
+
+/// Inline styles for `--html` pages, enough for readable headings, tables and code.
+const HTML_STYLESHEET: &str = "\
+body { max-width: 48em; margin: 2em auto; padding: 0 1em; font: 16px/1.5 sans-serif; color: #222; }
+h1, h2, h3 { line-height: 1.2; margin-top: 1.5em; }
+h1, h2 { border-bottom: 1px solid #ddd; padding-bottom: 0.2em; }
+code { font: 0.9em monospace; background: #f3f3f3; padding: 0.1em 0.3em; border-radius: 3px; }
+pre { background: #f6f8fa; padding: 0.8em 1em; overflow-x: auto; border-radius: 4px; }
+pre code { background: none; padding: 0; }
+blockquote { margin-left: 0; padding-left: 1em; border-left: 4px solid #ddd; color: #555; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #ddd; padding: 0.3em 0.6em; }
+";
+
+/// Context windows in tokens, keyed by model name prefix like `INPUT_PRICING`.
+const CONTEXT_LIMITS: &[(&str, u32)] = &[
+    ("gemini-2.5-", 1_048_576),
+    ("gemini-2.0-", 1_048_576),
+    ("gemini-1.5-flash", 1_048_576),
+    ("gemini-1.5-pro", 2_097_152),
+];
+/// Share of the context window past which a response is followed by a warning.
+const CONTEXT_WARNING_THRESHOLD: f64 = 0.9;
+
+/// Command-line options.
+#[derive(CliParser)]
+#[command(version, about = "Chat with Gemini from your terminal")]
+struct Cli {
+    /// Send this prompt, print the response and exit instead of starting the REPL
+    prompt: Vec<String>,
+    /// Model to use, e.g. gemini-2.5-pro or an alias such as flash. Defaults to the
+    /// GEMINI_MODEL environment variable, then `model` in the config file
+    #[arg(short, long, value_name = "NAME")]
+    model: Option<String>,
+    /// Don't print the "Thinking..." indicator or the response header
+    #[arg(short, long)]
+    quiet: bool,
+    /// Read the config from this file instead of ~/.config/gemini_cli/config.toml
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Disable colors and syntax highlighting (also enabled by the NO_COLOR env var)
+    #[arg(long)]
+    no_color: bool,
+    /// Draw bullets, rules, quote bars and the spinner with ASCII characters only
+    /// (the default when the locale isn't UTF-8)
+    #[arg(long)]
+    ascii: bool,
+    /// Non-interactive mode for CI: implies --quiet and --no-color, reads the prompt
+    /// from the arguments or stdin, and exits non-zero if the request fails
+    #[arg(long)]
+    ci: bool,
+    /// Render the response incrementally as it streams in
+    #[arg(long)]
+    stream: bool,
+    /// Print the prompt and response token counts after each response
+    #[arg(long)]
+    usage: bool,
+    /// Ask for token log probabilities and underline the tokens the model was unsure of
+    #[arg(long)]
+    logprobs: bool,
+    /// Log retries and other details of what the client is doing
+    #[arg(short, long)]
+    verbose: bool,
+    /// System instruction that steers every response, e.g. "Answer in French"
+    #[arg(long, value_name = "TEXT")]
+    system: Option<String>,
+    /// Upload a PDF or text file and send it with the first prompt (repeatable)
+    #[arg(long, value_name = "PATH")]
+    attach: Vec<PathBuf>,
+    /// Print responses exactly as received instead of rendering the markdown
+    #[arg(long)]
+    no_markdown: bool,
+    /// Pipe each response through this shell command, e.g. "glow -", and print
+    /// its output instead of rendering the markdown
+    #[arg(long, value_name = "COMMAND")]
+    pipe: Option<String>,
+    /// Send only the most recent N turns of the conversation to keep long chats
+    /// within the context window
+    #[arg(long, value_name = "N", value_parser = parse_history_limit)]
+    history_limit: Option<usize>,
+    /// Retry a request this many times when the connection fails or times out
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_NETWORK_RETRIES)]
+    retries: u32,
+    /// Send requests to this API endpoint instead of Google's, e.g. a proxy
+    #[arg(long, value_name = "URL")]
+    base_url: Option<String>,
+    /// Allow an http:// --base-url, which sends the API key unencrypted. For
+    /// testing against a local server only
+    #[arg(long, requires = "base_url")]
+    allow_http: bool,
+    /// Accept invalid TLS certificates, e.g. a self-signed proxy. For testing only
+    #[arg(long)]
+    insecure: bool,
+    /// Walk through setting up an API key, even when one is already set
+    #[arg(long, conflicts_with = "ci")]
+    interactive_auth: bool,
+    /// Speak only HTTP/1.1, for proxies that mishandle HTTP/2. For troubleshooting
+    #[arg(long)]
+    http1: bool,
+    /// Skip checking the API key and connection when the REPL starts
+    #[arg(long)]
+    no_check: bool,
+    /// Extra header for every request, e.g. "X-Trace-Id: abc" (repeatable)
+    #[arg(long = "header", value_name = "KEY: VALUE", value_parser = parse_header)]
+    headers: Vec<(HeaderName, HeaderValue)>,
+    /// Send the generateContent request body in FILE as-is, print the response and exit
+    #[arg(long, value_name = "FILE", conflicts_with = "prompt")]
+    raw_request: Option<PathBuf>,
+    /// Read the prompt from the terminal without echoing it, instead of from the
+    /// command line where it would end up in shell history, then exit
+    #[arg(long, conflicts_with = "prompt")]
+    no_input_echo: bool,
+    /// Don't use the instructions in the nearest .gemini.md file
+    #[arg(long)]
+    no_project_context: bool,
+    /// Let the 'run' command execute shell commands with the last response as input
+    #[arg(long)]
+    allow_exec: bool,
+    /// Send the text on the clipboard, after the prompt if one is given, then exit
+    #[arg(long)]
+    from_clipboard: bool,
+    /// Convert the response to a standalone HTML page instead of rendering it
+    /// for the terminal, then exit
+    #[arg(long, conflicts_with = "raw_request")]
+    html: bool,
+    /// Print only the part of the response this regex matches: its first capture
+    /// group, or the whole match. Exits with an error when nothing matches
+    #[arg(long, value_name = "REGEX", conflicts_with_all = ["html", "raw_request"])]
+    extract: Option<Regex>,
+    /// Send the prompt given on the command line, then stay in the REPL to
+    /// continue the conversation instead of exiting
+    #[arg(
+        long,
+        requires = "prompt",
+        conflicts_with_all = ["ci", "from_clipboard", "html", "extract", "raw_request"]
+    )]
+    once: bool,
+    /// Write the --html page to FILE instead of stdout
+    #[arg(short, long, value_name = "FILE", requires = "html")]
+    output: Option<PathBuf>,
+    /// Sampling temperature from 0.0 to 2.0; lower is more focused and deterministic
+    #[arg(long, value_parser = parse_temperature, help_heading = "Generation")]
+    temperature: Option<f32>,
+    /// Sample only from the K most likely tokens (a positive integer). Setting it
+    /// too low can make output repetitive
+    #[arg(long, value_name = "N", value_parser = parse_top_k, help_heading = "Generation")]
+    top_k: Option<u32>,
+    /// Fixed random seed, so repeated identical prompts give the same answer.
+    /// Combine with --temperature 0 for the most deterministic output
+    #[arg(long, value_name = "N", help_heading = "Generation")]
+    seed: Option<i32>,
+    /// Token budget for the model's thinking on models that support it: 0 turns
+    /// thinking off, -1 lets the model decide
+    #[arg(long, value_name = "N", value_parser = parse_thinking_budget, allow_negative_numbers = true, help_heading = "Generation")]
+    thinking_budget: Option<i32>,
+    /// Ask for N alternative responses (1 to 8) and show the one --select-candidate picks
+    #[arg(long, value_name = "N", value_parser = parse_candidate_count, help_heading = "Generation")]
+    candidates: Option<u32>,
+    /// Which of several candidates to show: its number, or "best" for the most
+    /// probable one (the longest when the API reports no probabilities)
+    #[arg(long, value_name = "N|best", default_value_t = CandidateSelection::default(), help_heading = "Generation")]
+    select_candidate: CandidateSelection,
+    /// Penalize tokens that already appear in the output, from -2.0 up to (not
+    /// including) 2.0. Positive values push the model toward new topics
+    #[arg(long, value_name = "X", value_parser = parse_penalty, allow_negative_numbers = true, help_heading = "Generation")]
+    presence_penalty: Option<f32>,
+    /// Penalize tokens by how often they already appear in the output, from -2.0
+    /// up to (not including) 2.0. Positive values reduce repeated phrases
+    #[arg(long, value_name = "X", value_parser = parse_penalty, allow_negative_numbers = true, help_heading = "Generation")]
+    frequency_penalty: Option<f32>,
+    /// Generation parameters as generationConfig JSON, e.g. '{"temperature":0.2,"topK":40}'.
+    /// The flags above override the same parameters given here
+    #[arg(long, value_name = "JSON", value_parser = parse_gen_config, help_heading = "Generation")]
+    gen_config: Option<(GenerationConfig, Vec<String>)>,
+}
+
+/// Options read from `config.toml` in the config directory. Every key is
+/// optional.
+#[derive(Deserialize)]
+#[serde(default)]
+struct Config {
+    /// Model to use when neither `--model` nor `GEMINI_MODEL` names one.
+    model: Option<String>,
+    /// Line editing keybindings for the REPL prompt.
+    edit_mode: EditMode,
+    /// bat theme for code blocks.
+    theme: Option<String>,
+    /// Show an indicator while waiting for a response.
+    thinking: bool,
+    /// Text of that indicator, instead of "Thinking...".
+    thinking_message: Option<String>,
+    /// Messages that replace it while a response is slow to arrive, as
+    /// `[[slow_messages]]` tables. Defaults to `DEFAULT_SLOW_MESSAGES`.
+    slow_messages: Option<Vec<SlowMessage>>,
+    /// Generation defaults per model, as `[models."<name>"]` tables.
+    models: HashMap<String, ModelConfig>,
+    /// REPL commands that run a shell command, from the `[commands]` table.
+    commands: HashMap<String, CustomCommand>,
+    /// Most prompts kept in the history file; older ones are dropped.
+    history_size: usize,
+    /// Don't add a prompt to the history when it repeats the one before.
+    history_dedup: bool,
+    /// How much randomness network retries add to their backoff.
+    retry_jitter: Jitter,
+    /// Gemini API key, used when `GEMINI_API_KEY` isn't set.
+    api_key: Option<String>,
+    /// Value of the `x-goog-api-client` header, instead of `DEFAULT_API_CLIENT`.
+    /// Empty to leave the header out.
+    api_client: Option<String>,
+    /// Template every prompt is put into before it is sent, with `{prompt}`
+    /// standing for the prompt.
+    prompt_wrapper: Option<String>,
+    /// Short names for models, from the `[aliases]` table.
+    aliases: HashMap<String, String>,
+    /// Most idle connections kept open to the API host for reuse, instead of
+    /// reqwest's default of no limit.
+    pool_max_idle_per_host: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            model: None,
+            edit_mode: EditMode::default(),
+            theme: None,
+            thinking: true,
+            thinking_message: None,
+            slow_messages: None,
+            models: HashMap::new(),
+            commands: HashMap::new(),
+            history_size: DEFAULT_HISTORY_SIZE,
+            history_dedup: true,
+            retry_jitter: Jitter::default(),
+            api_key: None,
+            api_client: None,
+            prompt_wrapper: None,
+            aliases: HashMap::new(),
+            pool_max_idle_per_host: None,
+        }
+    }
+}
+
+/// A REPL command defined in the config file. `{args}` in `run` is replaced
+/// with whatever follows the command name. With `context`, the output is also
+/// sent along with the next prompt.
+#[derive(Deserialize)]
+#[serde(from = "CustomCommandConfig")]
+struct CustomCommand {
+    run: String,
+    context: bool,
+}
+
+/// How a custom command is written: `name = "command"`, or
+/// `name = { run = "command", context = true }` to use its output.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CustomCommandConfig {
+    Run(String),
+    Table {
+        run: String,
+        #[serde(default)]
+        context: bool,
+    },
+}
+
+impl From<CustomCommandConfig> for CustomCommand {
+    fn from(config: CustomCommandConfig) -> Self {
+        match config {
+            CustomCommandConfig::Run(run) => CustomCommand {
+                run,
+                context: false,
             },
-            Event::Text(text) => {
-                if in_code_block {
-                    code_buffer.push_str(&text);
+            CustomCommandConfig::Table { run, context } => CustomCommand { run, context },
+        }
+    }
+}
+
+/// Drops custom commands that would shadow a built-in one or can't be typed
+/// as a single word, with a warning for each.
+fn custom_commands(commands: HashMap<String, CustomCommand>) -> HashMap<String, CustomCommand> {
+    let t = theme();
+    commands
+        .into_iter()
+        .filter(|(name, _)| {
+            let problem =
+                if COMMANDS.contains(&name.as_str()) || HIDDEN_COMMANDS.contains(&name.as_str()) {
+                    "it is a built-in command"
+                } else if name.is_empty() || name.contains(char::is_whitespace) {
+                    "command names must be a single word"
                 } else {
-                    flush_newlines(&mut pending_newlines, 0);
-                    render_text(&text, &wrap_options, &list_stack, &mut at_line_start);
-                }
-            }
-            Event::Code(text) => {
-                print!("{}`{}`{}", KEYWORD_COLOR, text, RESET);
-                at_line_start = false;
-            }
-            Event::HardBreak => {
-                print!("\n");
-                at_line_start = true;
+                    return true;
+                };
+            eprintln!(
+                "{}Warning:{} Ignoring custom command '{}': {}.",
+                t.yellow, t.reset, name, problem
+            );
+            false
+        })
+        .collect()
+}
+
+/// A message shown instead of "Thinking..." once a response has taken `after` seconds.
+#[derive(Deserialize, Clone)]
+struct SlowMessage {
+    after: u64,
+    message: String,
+}
+
+/// Generation defaults for one model in the config file.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ModelConfig {
+    temperature: Option<f32>,
+    top_k: Option<u32>,
+    seed: Option<i32>,
+    thinking_budget: Option<i32>,
+}
+
+impl From<ModelConfig> for GenerationConfig {
+    fn from(config: ModelConfig) -> Self {
+        GenerationConfig {
+            temperature: config.temperature,
+            top_k: config.top_k,
+            seed: config.seed,
+            thinking_config: config.thinking_budget.map(ThinkingConfig::budget),
+            candidate_count: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            response_logprobs: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum EditMode {
+    #[default]
+    Emacs,
+    Vi,
+}
+
+impl From<EditMode> for rustyline::EditMode {
+    fn from(mode: EditMode) -> Self {
+        match mode {
+            EditMode::Emacs => rustyline::EditMode::Emacs,
+            EditMode::Vi => rustyline::EditMode::Vi,
+        }
+    }
+}
+
+/// How a network retry's backoff is randomized, so that many clients failing
+/// at once don't all retry at the same moment.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Jitter {
+    /// Anywhere from no wait up to the full backoff.
+    #[default]
+    Full,
+    /// Half the backoff, plus up to the other half.
+    Equal,
+    /// Exactly the backoff.
+    None,
+}
+
+/// Finds the keys in a config file that `Config` doesn't know, which serde
+/// would otherwise skip without a word. Each comes with its dotted path and
+/// the known key it is most likely a typo of, if any is close.
+fn unknown_config_keys(config: &toml::Table) -> Vec<(String, Option<&'static str>)> {
+    fn check(
+        table: &toml::Table,
+        path: &str,
+        known: &[&'static str],
+        unknown: &mut Vec<(String, Option<&'static str>)>,
+    ) {
+        for key in table.keys() {
+            if !known.contains(&key.as_str()) {
+                let suggestion = known
+                    .iter()
+                    .map(|known| (strsim::levenshtein(key, known), *known))
+                    .min()
+                    .filter(|(distance, _)| *distance <= 2)
+                    .map(|(_, known)| known);
+                unknown.push((format!("{}{}", path, key), suggestion));
             }
-            Event::SoftBreak => {
-                if !at_line_start {
-                    print!(" ");
+        }
+    }
+
+    let mut unknown = Vec::new();
+    check(config, "", CONFIG_KEYS, &mut unknown);
+    let tables = |key: &str| {
+        config
+            .get(key)
+            .and_then(toml::Value::as_table)
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, value)| Some((name.clone(), value.as_table()?)))
+    };
+    for (name, table) in tables("models") {
+        check(
+            table,
+            &format!("models.{}.", name),
+            MODEL_CONFIG_KEYS,
+            &mut unknown,
+        );
+    }
+    // Commands may also be plain strings, which have no keys to get wrong.
+    for (name, table) in tables("commands") {
+        check(
+            table,
+            &format!("commands.{}.", name),
+            CUSTOM_COMMAND_KEYS,
+            &mut unknown,
+        );
+    }
+    let slow_messages = config.get("slow_messages").and_then(toml::Value::as_array);
+    for (i, message) in slow_messages.into_iter().flatten().enumerate() {
+        if let Some(message) = message.as_table() {
+            check(
+                message,
+                &format!("slow_messages[{}].", i),
+                SLOW_MESSAGE_KEYS,
+                &mut unknown,
+            );
+        }
+    }
+    unknown
+}
+
+/// Finds the nearest `PROJECT_CONTEXT_FILE`, walking up from the current
+/// directory the way git looks for `.git`.
+fn find_project_context() -> Option<PathBuf> {
+    let dir = env::current_dir().ok()?;
+    dir.ancestors()
+        .map(|dir| dir.join(PROJECT_CONTEXT_FILE))
+        .find(|path| path.is_file())
+}
+
+/// The system instruction a conversation starts with: the project context
+/// file, if there is one, followed by `--system`. Either can be empty.
+fn starting_system_instruction(cli: &Cli, verbose: bool) -> Option<String> {
+    let t = theme();
+    let project = match find_project_context().filter(|_| !cli.no_project_context) {
+        Some(path) => match fs::read_to_string(&path) {
+            Ok(text) => {
+                if verbose {
+                    eprintln!(
+                        "{}Using project context from {}{}",
+                        t.dim,
+                        path.display(),
+                        t.reset
+                    );
                 }
+                Some(text.trim().to_string()).filter(|text| !text.is_empty())
             }
-            Event::Rule => {
-                flush_newlines(&mut pending_newlines, 1);
-                println!("{}{}{}", DIM, "─".repeat(wrap_width.min(50)), RESET);
-                pending_newlines = pending_newlines.max(1);
-                at_line_start = true;
+            Err(e) => {
+                eprintln!(
+                    "{}Warning:{} Could not read {}: {}",
+                    t.yellow,
+                    t.reset,
+                    path.display(),
+                    e
+                );
+                None
             }
-            Event::Html(html) => {
-                // Basic HTML tag stripping for cleaner output
-                if !html.trim().is_empty() && !html.starts_with('<') {
-                    flush_newlines(&mut pending_newlines, 0);
-                    print!("{}", html);
-                    at_line_start = false;
+        },
+        None => None,
+    };
+    match (project, cli.system.clone()) {
+        (Some(project), Some(system)) => Some(format!("{}\n\n{}", project, system)),
+        (project, system) => project.or(system),
+    }
+}
+
+fn config_path() -> PathBuf {
+    config_dir().join("config.toml")
+}
+
+/// Loads the config file at `path`. A missing file means defaults; an
+/// invalid one is reported and ignored so a typo never locks the user out of
+/// the REPL. A file named with `--config` (`explicit`) must exist and be
+/// valid, though, since falling back to defaults would hide the mistake.
+fn load_config(path: &Path, explicit: bool) -> Result<Config, String> {
+    let t = theme();
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if explicit => return Err(format!("Could not read {}: {}", path.display(), e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => {
+            eprintln!(
+                "{}Warning:{} Could not read {}: {}",
+                t.yellow,
+                t.reset,
+                path.display(),
+                e
+            );
+            return Ok(Config::default());
+        }
+    };
+    match toml::from_str(&text) {
+        Ok(config) => {
+            if let Ok(table) = toml::from_str(&text) {
+                for (key, suggestion) in unknown_config_keys(&table) {
+                    match suggestion {
+                        Some(suggestion) => eprintln!(
+                            "{}Warning:{} Unknown config key '{}' in {}, did you mean '{}'?",
+                            t.yellow,
+                            t.reset,
+                            key,
+                            path.display(),
+                            suggestion
+                        ),
+                        None => eprintln!(
+                            "{}Warning:{} Unknown config key '{}' in {}",
+                            t.yellow,
+                            t.reset,
+                            key,
+                            path.display()
+                        ),
+                    }
                 }
             }
-            _ => {}
+            Ok(config)
+        }
+        Err(e) if explicit => Err(format!("Invalid config {}: {}", path.display(), e)),
+        Err(e) => {
+            eprintln!(
+                "{}Warning:{} Ignoring invalid config {}: {}",
+                t.yellow,
+                t.reset,
+                path.display(),
+                e
+            );
+            Ok(Config::default())
         }
     }
+}
 
-    // Final cleanup
-    if !at_line_start {
-        print!("\n");
-    }
-    io::stdout().flush().unwrap();
+/// Runtime settings that can be changed from the REPL with `set <key> <value>`.
+#[derive(Clone)]
+struct Settings {
+    /// The model prompts are sent to.
+    model: String,
+    /// Render inline code on a background color instead of wrapping it in backticks.
+    inline_bg: bool,
+    /// Ask for confirmation before sending prompts of at least `cost_threshold` tokens.
+    confirm_cost: bool,
+    cost_threshold: u32,
+    /// Render responses incrementally as they stream in.
+    stream: bool,
+    /// Milliseconds to pause between words of a streamed response, or 0 to show it at once.
+    stream_delay: u64,
+    /// Dim struck-through text as well, for terminals without strikethrough.
+    strike_dim: bool,
+    /// bat theme for code blocks, or bat's default when unset.
+    theme: Option<String>,
+    /// How responses are displayed.
+    render: RenderMode,
+    /// What pressing Enter on an empty prompt does.
+    empty_input: EmptyInput,
+    /// The indicator shown while waiting for a response, or `None` for none.
+    thinking_message: Option<String>,
+    /// Messages for slow responses, in increasing order of `after`.
+    slow_messages: Vec<SlowMessage>,
+    /// Shell command responses are piped through instead of the built-in renderer.
+    pipe: Option<String>,
+    /// Template each prompt is wrapped in before it is sent; see `wrap_prompt`.
+    prompt_wrapper: Option<String>,
+    /// Show the model, temperature and conversation size above the prompt.
+    statusline: bool,
+    /// Highlight math spans and show simple LaTeX as Unicode.
+    math: bool,
+    /// Print the token counts the API reports after each response.
+    usage: bool,
+    /// Ask for token log probabilities and underline the low-confidence tokens.
+    logprobs: bool,
+    /// Number every line of a rendered response, code blocks included.
+    line_numbers: bool,
+    /// Send the request again once when a response comes back without text.
+    retry_empty: bool,
+    /// Pretty-print and highlight JSON that a response gives outside a code block.
+    autojson: bool,
+    /// Render responses that are JSON, CSV or TSV, or bare code, as such rather than as markdown.
+    autorender: bool,
+    /// Break words longer than the wrap width instead of letting them overflow.
+    break_long: bool,
+    /// Follow mermaid diagrams with a link that opens them in the Mermaid Live Editor.
+    mermaid_link: bool,
+    /// Ask thinking models for a summary of their thoughts and show it, dimmed,
+    /// before the answer.
+    show_thoughts: bool,
+    /// Show a response that is only a code block without bat's grid and line numbers.
+    bare_code: bool,
+    /// Drop blank lines at the start and end of a response before rendering it.
+    trim: bool,
+    /// Log retries and other details of what the client is doing.
+    verbose: bool,
+    /// Print the text of each prompt as sent, after `@file:` expansion.
+    echo_prompt: bool,
+    /// How many times to retry a request that failed to connect or timed out.
+    retries: u32,
+    /// How the wait before each of those retries is randomized.
+    retry_jitter: Jitter,
+    /// Which candidate to show when `candidates` asks for more than one.
+    select_candidate: CandidateSelection,
+    /// Send only the most recent this many turns, or the whole conversation when unset.
+    history_limit: Option<usize>,
+    /// Generation parameters from command-line flags and `set`.
+    generation: GenerationConfig,
+    /// Generation defaults for particular models, from `[models.<name>]` in the config file.
+    model_defaults: HashMap<String, GenerationConfig>,
+    /// Short names for models, built in or from `[aliases]` in the config file.
+    aliases: HashMap<String, String>,
 }
 
-fn flush_newlines(pending: &mut usize, min_newlines: usize) {
-    let newlines_to_print = (*pending).max(min_newlines);
-    for _ in 0..newlines_to_print {
-        print!("\n");
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            model: DEFAULT_MODEL.to_string(),
+            inline_bg: false,
+            confirm_cost: false,
+            cost_threshold: 10_000,
+            stream: false,
+            stream_delay: 0,
+            strike_dim: true,
+            theme: None,
+            render: RenderMode::Markdown,
+            empty_input: EmptyInput::Ignore,
+            thinking_message: Some(DEFAULT_THINKING_MESSAGE.to_string()),
+            slow_messages: default_slow_messages(),
+            pipe: None,
+            prompt_wrapper: None,
+            statusline: false,
+            math: false,
+            usage: false,
+            logprobs: false,
+            line_numbers: false,
+            retry_empty: false,
+            autojson: false,
+            autorender: false,
+            break_long: false,
+            mermaid_link: false,
+            show_thoughts: false,
+            bare_code: false,
+            trim: true,
+            verbose: false,
+            echo_prompt: false,
+            retries: DEFAULT_NETWORK_RETRIES,
+            retry_jitter: Jitter::default(),
+            select_candidate: CandidateSelection::default(),
+            history_limit: None,
+            generation: GenerationConfig::default(),
+            model_defaults: HashMap::new(),
+            aliases: model_aliases(HashMap::new()),
+        }
     }
-    *pending = 0;
 }
 
-fn render_text(
-    text: &str,
-    wrap_options: &Options,
-    list_stack: &[(Option<u64>, usize)],
-    at_line_start: &mut bool,
-) {
-    let current_indent = if !list_stack.is_empty() {
-        let indent_level = list_stack.len() - 1;
-        "  ".repeat(indent_level + 1) // +1 for alignment with list marker
-    } else {
-        String::new()
-    };
-
-    let text = text.trim_start_matches('\n').trim_end_matches('\n');
-    if text.is_empty() {
-        return;
+impl Settings {
+    /// The active model, followed by its alias when it has one, e.g.
+    /// `gemini-2.5-flash (flash)`.
+    fn model_label(&self) -> String {
+        let alias = self
+            .aliases
+            .iter()
+            .filter(|(_, model)| **model == self.model)
+            .map(|(alias, _)| alias)
+            .min();
+        match alias {
+            Some(alias) => format!("{} ({})", self.model, alias),
+            None => self.model.clone(),
+        }
     }
 
-    // Handle blockquote prefix
-    let lines: Vec<&str> = text.lines().collect();
-    for (i, line) in lines.iter().enumerate() {
-        if *at_line_start && !current_indent.is_empty() && i > 0 {
-            print!("{}", current_indent);
+    fn render_options(&self) -> RenderOptions {
+        RenderOptions {
+            render: self.render,
+            inline_bg: self.inline_bg,
+            strike_dim: self.strike_dim,
+            theme: self.theme.clone(),
+            math: self.math,
+            line_numbers: self.line_numbers,
+            autojson: self.autojson,
+            autorender: self.autorender,
+            break_long: self.break_long,
+            mermaid_link: self.mermaid_link,
+            bare_code: self.bare_code,
+            trim: self.trim,
         }
+    }
 
-        // Wrap the line if it's too long
-        let effective_width = wrap_options.width.saturating_sub(current_indent.len());
-        let wrapped_lines = wrap(line, effective_width);
+    /// Puts `prompt` into the wrapper template, if one is set.
+    fn wrap_prompt(&self, prompt: String) -> String {
+        match &self.prompt_wrapper {
+            Some(template) => template.replace(PROMPT_PLACEHOLDER, &prompt),
+            None => prompt,
+        }
+    }
 
-        for (j, wrapped_line) in wrapped_lines.iter().enumerate() {
-            if j > 0 {
-                print!("\n{}", current_indent);
+    fn apply(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "inline-bg" => self.inline_bg = parse_switch(value)?,
+            "confirm-cost" => self.confirm_cost = parse_switch(value)?,
+            "cost-threshold" => self.cost_threshold = parse_number(value)?,
+            "retries" => self.retries = parse_number(value)?,
+            "stream" => self.stream = parse_switch(value)?,
+            "stream-delay" => self.stream_delay = parse_number(value)?,
+            "strike-dim" => self.strike_dim = parse_switch(value)?,
+            "math" => self.math = parse_switch(value)?,
+            "statusline" => self.statusline = parse_switch(value)?,
+            "usage" => self.usage = parse_switch(value)?,
+            "logprobs" => self.logprobs = parse_switch(value)?,
+            "line-numbers" => self.line_numbers = parse_switch(value)?,
+            "retry-empty" => self.retry_empty = parse_switch(value)?,
+            "autojson" => self.autojson = parse_switch(value)?,
+            "autorender" => self.autorender = parse_switch(value)?,
+            "break-long" => self.break_long = parse_switch(value)?,
+            "mermaid-link" => self.mermaid_link = parse_switch(value)?,
+            "show-thoughts" => self.show_thoughts = parse_switch(value)?,
+            "bare-code" => self.bare_code = parse_switch(value)?,
+            "trim" => self.trim = parse_switch(value)?,
+            "verbose" => self.verbose = parse_switch(value)?,
+            "echo-prompt" => self.echo_prompt = parse_switch(value)?,
+            "render" => self.render = value.parse()?,
+            "empty-input" => self.empty_input = value.parse()?,
+            "select-candidate" => self.select_candidate = value.parse()?,
+            "pipe" if value == "off" => self.pipe = None,
+            "pipe" => self.pipe = Some(value.to_string()),
+            "wrapper" if value == "none" => self.prompt_wrapper = None,
+            "wrapper" => self.prompt_wrapper = Some(parse_prompt_wrapper(value)?),
+            "theme" if value == "default" => self.theme = None,
+            "theme" => {
+                validate_theme(value)?;
+                self.theme = Some(value.to_string());
+            }
+            "temperature" => {
+                self.generation.temperature = parse_optional(value, parse_temperature)?
+            }
+            "top-k" => self.generation.top_k = parse_optional(value, parse_top_k)?,
+            "seed" => self.generation.seed = parse_optional(value, parse_number)?,
+            "thinking-budget" => {
+                self.generation.thinking_config =
+                    parse_optional(value, parse_thinking_budget)?.map(ThinkingConfig::budget)
             }
-            print!("{}", wrapped_line);
+            "candidates" => {
+                self.generation.candidate_count = parse_optional(value, parse_candidate_count)?
+            }
+            "presence-penalty" => {
+                self.generation.presence_penalty = parse_optional(value, parse_penalty)?
+            }
+            "frequency-penalty" => {
+                self.generation.frequency_penalty = parse_optional(value, parse_penalty)?
+            }
+            "history-limit" => self.history_limit = parse_optional(value, parse_history_limit)?,
+            _ => return Err(format!("Unknown setting '{}'", key)),
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> Vec<(&'static str, String)> {
+        let generation = self.generation_config();
+        vec![
+            ("model", self.model.clone()),
+            ("inline-bg", switch_label(self.inline_bg).to_string()),
+            ("confirm-cost", switch_label(self.confirm_cost).to_string()),
+            ("cost-threshold", self.cost_threshold.to_string()),
+            ("retries", self.retries.to_string()),
+            ("stream", switch_label(self.stream).to_string()),
+            ("stream-delay", self.stream_delay.to_string()),
+            ("strike-dim", switch_label(self.strike_dim).to_string()),
+            ("math", switch_label(self.math).to_string()),
+            ("statusline", switch_label(self.statusline).to_string()),
+            ("usage", switch_label(self.usage).to_string()),
+            ("logprobs", switch_label(self.logprobs).to_string()),
+            ("line-numbers", switch_label(self.line_numbers).to_string()),
+            ("retry-empty", switch_label(self.retry_empty).to_string()),
+            ("autojson", switch_label(self.autojson).to_string()),
+            ("autorender", switch_label(self.autorender).to_string()),
+            ("break-long", switch_label(self.break_long).to_string()),
+            ("mermaid-link", switch_label(self.mermaid_link).to_string()),
+            (
+                "show-thoughts",
+                switch_label(self.show_thoughts).to_string(),
+            ),
+            ("bare-code", switch_label(self.bare_code).to_string()),
+            ("trim", switch_label(self.trim).to_string()),
+            ("verbose", switch_label(self.verbose).to_string()),
+            ("echo-prompt", switch_label(self.echo_prompt).to_string()),
+            ("render", self.render.to_string()),
+            ("empty-input", self.empty_input.to_string()),
+            ("select-candidate", self.select_candidate.to_string()),
+            (
+                "pipe",
+                self.pipe.clone().unwrap_or_else(|| "off".to_string()),
+            ),
+            (
+                "wrapper",
+                self.prompt_wrapper
+                    .clone()
+                    .unwrap_or_else(|| "none".to_string()),
+            ),
+            ("theme", optional_label(self.theme.as_deref())),
+            (
+                "history-limit",
+                self.history_limit
+                    .map_or_else(|| "off".to_string(), |limit| limit.to_string()),
+            ),
+            ("temperature", optional_label(generation.temperature)),
+            ("top-k", optional_label(generation.top_k)),
+            ("seed", optional_label(generation.seed)),
+            (
+                "thinking-budget",
+                optional_label(generation.thinking_budget()),
+            ),
+            ("candidates", optional_label(generation.candidate_count)),
+            (
+                "presence-penalty",
+                optional_label(generation.presence_penalty),
+            ),
+            (
+                "frequency-penalty",
+                optional_label(generation.frequency_penalty),
+            ),
+        ]
+    }
+
+    /// Resolves the generation parameters for the active model. Each layer
+    /// only fills in what the ones above it leave unset, from highest to lowest:
+    /// `set`, command-line flags (both stored in `generation`, so `set` simply
+    /// overwrites a flag), `[models.<name>]` in the config file, and finally
+    /// the model's own defaults on the API side.
+    fn generation_config(&self) -> GenerationConfig {
+        let model_defaults = self.model_defaults.get(&self.model).cloned();
+        let mut generation = self
+            .generation
+            .clone()
+            .or(model_defaults.unwrap_or_default());
+        if self.show_thoughts {
+            generation
+                .thinking_config
+                .get_or_insert_with(ThinkingConfig::default)
+                .include_thoughts = true;
         }
+        if self.logprobs {
+            generation.response_logprobs = Some(true);
+        }
+        generation
+    }
 
-        if i < lines.len() - 1 {
-            print!("\n");
-            *at_line_start = true;
-        } else {
-            *at_line_start = false;
+    fn print(&self) {
+        let t = theme();
+        println!("{}Settings:{}", t.bold, t.reset);
+        for (key, value) in self.entries() {
+            println!("  {}{:<16}{} = {}", t.keyword, key, t.reset, value);
         }
     }
 }
 
-fn render_code_block(code: &str, language: &str) {
-    if code.trim().is_empty() {
-        return;
-    }
+/// What Enter on an empty prompt does, set with `set empty-input`.
+#[derive(Clone, Copy, PartialEq)]
+enum EmptyInput {
+    /// Nothing; the prompt is shown again.
+    Ignore,
+    /// Sends the last prompt again.
+    Repeat,
+    /// Starts a multi-line prompt, see `ends_multiline_prompt`.
+    Newline,
+}
 
-    // Try to use bat for syntax highlighting, fallback to simple display
-    match PrettyPrinter::new()
-        .input_from_bytes(code.trim_end().as_bytes())
-        .language(language)
-        .line_numbers(true)
-        .grid(true)
-        .header(false)
-        .rule(false)
-        .print()
-    {
-        Ok(_) => {}
-        Err(_) => {
-            // Fallback: simple code block rendering
-            println!("{}┌{}", DIM, "─".repeat(50));
-            for line in code.lines() {
-                println!("{}│{} {}", DIM, RESET, line);
-            }
-            println!("{}└{}{}", DIM, "─".repeat(50), RESET);
+impl std::str::FromStr for EmptyInput {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value {
+            "ignore" => Ok(EmptyInput::Ignore),
+            "repeat" => Ok(EmptyInput::Repeat),
+            "newline" => Ok(EmptyInput::Newline),
+            _ => Err(format!(
+                "Expected 'ignore', 'repeat' or 'newline', got '{}'",
+                value
+            )),
         }
     }
 }
 
-#[derive(Serialize)]
-struct GeminiRequest {
-    contents: Vec<Content>,
+impl std::fmt::Display for EmptyInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            EmptyInput::Ignore => "ignore",
+            EmptyInput::Repeat => "repeat",
+            EmptyInput::Newline => "newline",
+        })
+    }
 }
-#[derive(Serialize)]
-struct Content {
-    parts: Vec<Part>,
+
+/// Which candidate to show when several are asked for, set with
+/// `--select-candidate` or `set select-candidate`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum CandidateSelection {
+    /// The candidate with this number, counting from 1.
+    Number(usize),
+    /// The one with the highest average log probability, or failing that the longest.
+    Best,
 }
-#[derive(Serialize)]
-struct Part {
-    text: String,
+
+impl Default for CandidateSelection {
+    fn default() -> Self {
+        CandidateSelection::Number(1)
+    }
 }
-#[derive(Deserialize)]
-struct GeminiResponse {
-    candidates: Vec<Candidate>,
+
+impl std::str::FromStr for CandidateSelection {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value {
+            "best" => Ok(CandidateSelection::Best),
+            _ => match value.parse::<usize>() {
+                Ok(number) if (1..=MAX_CANDIDATE_COUNT as usize).contains(&number) => {
+                    Ok(CandidateSelection::Number(number))
+                }
+                _ => Err(format!(
+                    "Expected 'best' or a number from 1 to {}, got '{}'",
+                    MAX_CANDIDATE_COUNT, value
+                )),
+            },
+        }
+    }
 }
-#[derive(Deserialize)]
-struct Candidate {
-    content: ResponseContent,
+
+impl std::fmt::Display for CandidateSelection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CandidateSelection::Number(number) => write!(f, "{}", number),
+            CandidateSelection::Best => f.write_str("best"),
+        }
+    }
 }
-#[derive(Deserialize)]
-struct ResponseContent {
-    parts: Vec<ResponsePart>,
+
+/// Whether Enter should submit `input` when `set empty-input newline` is on.
+/// Enter on an empty prompt starts a multi-line prompt instead, in which
+/// Enter only breaks lines, so single blank lines can separate paragraphs;
+/// Enter on a second blank line in a row sends it.
+fn ends_multiline_prompt(input: &str) -> bool {
+    (!input.is_empty() && !input.starts_with('\n')) || input.ends_with("\n\n")
 }
-#[derive(Deserialize)]
-struct ResponsePart {
-    text: String,
+
+fn default_slow_messages() -> Vec<SlowMessage> {
+    DEFAULT_SLOW_MESSAGES
+        .iter()
+        .map(|&(after, message)| SlowMessage {
+            after,
+            message: message.to_string(),
+        })
+        .collect()
 }
 
-async fn send_to_gemini(
-    client: &Client,
-    api_key: &str,
-    text: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let url = format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/gemini-2.5-flash-lite-preview-06-17:generateContent?key={}",
-        api_key
-    );
-    let request_body = GeminiRequest {
-        contents: vec![Content {
-            parts: vec![Part {
-                text: text.to_string(),
-            }],
-        }],
-    };
-    let response = client.post(&url).json(&request_body).send().await?;
-    if !response.status().is_success() {
-        let error_text = response.text().await?;
-        return Err(format!("API Error: {}", error_text).into());
+fn parse_switch(value: &str) -> Result<bool, String> {
+    match value {
+        "on" | "true" | "yes" => Ok(true),
+        "off" | "false" | "no" => Ok(false),
+        _ => Err(format!("Expected 'on' or 'off', got '{}'", value)),
     }
-    let gemini_response: GeminiResponse = response.json().await?;
-    if let Some(candidate) = gemini_response.candidates.first() {
-        if let Some(part) = candidate.content.parts.first() {
-            return Ok(part.text.clone());
-        }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Expected a number, got '{}'", value))
+}
+
+/// Parses a setting that can also be unset with `off`.
+fn parse_optional<T>(
+    value: &str,
+    parse: fn(&str) -> Result<T, String>,
+) -> Result<Option<T>, String> {
+    if value == "off" {
+        Ok(None)
+    } else {
+        parse(value).map(Some)
     }
-    Err("No response content found".into())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let api_key = env::var("GEMINI_API_KEY").expect("GEMINI_API_KEY environment variable not set");
-    let client = Client::new();
+fn parse_temperature(value: &str) -> Result<f32, String> {
+    let temperature: f32 = parse_number(value)?;
+    if (0.0..=2.0).contains(&temperature) {
+        Ok(temperature)
+    } else {
+        Err(format!(
+            "Temperature must be between 0.0 and 2.0, got {}",
+            value
+        ))
+    }
+}
 
-    println!();
+fn parse_top_k(value: &str) -> Result<u32, String> {
+    match value.parse::<u32>() {
+        Ok(top_k) if top_k > 0 => Ok(top_k),
+        _ => Err(format!("top-k must be a positive integer, got '{}'", value)),
+    }
+}
 
-    loop {
-        print!("{}> {}", MAGENTA, RESET);
-        io::stdout().flush().expect("Failed to flush stdout");
+fn parse_candidate_count(value: &str) -> Result<u32, String> {
+    match value.parse::<u32>() {
+        Ok(count) if (1..=MAX_CANDIDATE_COUNT).contains(&count) => Ok(count),
+        _ => Err(format!(
+            "The candidate count must be 1 to {}, got '{}'",
+            MAX_CANDIDATE_COUNT, value
+        )),
+    }
+}
 
-        let mut input = String::new();
-        if io::stdin().read_line(&mut input).is_err() {
-            eprintln!("{}Error reading input.{}", RED, RESET);
-            break;
-        }
+/// The API accepts both penalties from -2.0 up to, but not including, 2.0.
+fn parse_penalty(value: &str) -> Result<f32, String> {
+    let penalty: f32 = parse_number(value)?;
+    if (-2.0..2.0).contains(&penalty) {
+        Ok(penalty)
+    } else {
+        Err(format!(
+            "Penalties must be from -2.0 up to but not including 2.0, got {}",
+            value
+        ))
+    }
+}
 
-        let input = input.trim();
+/// The built-in aliases with those from the config file on top.
+fn model_aliases(configured: HashMap<String, String>) -> HashMap<String, String> {
+    let mut aliases: HashMap<String, String> = BUILT_IN_ALIASES
+        .iter()
+        .map(|(alias, model)| (alias.to_string(), model.to_string()))
+        .collect();
+    aliases.extend(configured);
+    aliases
+}
 
-        match input {
-            "" => continue,
-            "quit" | "exit" => {
-                println!("{}Goodbye!{}", YELLOW, RESET);
-                break;
-            }
-            "clear" => {
-                print!("\x1b[2J\x1b[H");
-                io::stdout().flush().unwrap();
-                continue;
-            }
-            "help" => {
-                println!("{}Available Commands:{}", BOLD, RESET);
-                println!(
-                    "  {}help{}      - Show this help message",
-                    KEYWORD_COLOR, RESET
-                );
-                println!(
-                    "  {}clear{}     - Clear the terminal screen",
-                    KEYWORD_COLOR, RESET
-                );
-                println!("  {}quit/exit{} - Exit the REPL", KEYWORD_COLOR, RESET);
-                println!("\nJust type any other message to chat with Gemini!");
-                continue;
-            }
-            _ => {
-                print!("\r{}Thinking...{}", YELLOW, RESET);
-                io::stdout().flush().unwrap();
+/// Resolves `name` if it is an alias, and checks the model name it ends up with.
+fn resolve_model_name(name: &str, aliases: &HashMap<String, String>) -> Result<String, String> {
+    let model = aliases.get(name).map_or(name, String::as_str);
+    validate_model_name(model)?;
+    Ok(model.to_string())
+}
 
-                match send_to_gemini(&client, &api_key, input).await {
-                    Ok(response) => {
-                        print!("\r{}\r", " ".repeat(15)); // Clear "Thinking..."
-                        println!("{}Gemini:{}", BOLD, RESET);
-                        render_markdown(&response);
-                    }
-                    Err(e) => {
-                        print!("\r{}\r", " ".repeat(15));
-                        eprintln!("{}Error:{} {}", RED, RESET, e);
-                    }
-                }
+/// Picks the model to start with: `--model`, then `GEMINI_MODEL`, then
+/// `model` in the config file, then `DEFAULT_MODEL`, resolving aliases. An
+/// invalid `--model` is an error; invalid names from the environment or
+/// config file are skipped with a warning.
+fn resolve_model(
+    flag: Option<String>,
+    config: Option<String>,
+    config_file: &Path,
+    aliases: &HashMap<String, String>,
+) -> Result<String, String> {
+    let t = theme();
+    if let Some(flag) = flag {
+        return resolve_model_name(&flag, aliases).map_err(|e| format!("--model: {}", e));
+    }
+    let env = env::var(MODEL_ENV)
+        .ok()
+        .filter(|name| !name.trim().is_empty());
+    let candidates = [
+        (env, format!("{} environment variable", MODEL_ENV)),
+        (config, format!("model in {}", config_file.display())),
+    ];
+    let model = candidates.into_iter().find_map(|(name, source)| {
+        match resolve_model_name(name?.trim(), aliases) {
+            Ok(model) => Some(model),
+            Err(e) => {
+                eprintln!(
+                    "{}Warning:{} Ignoring the {}: {}",
+                    t.yellow, t.reset, source, e
+                );
+                None
             }
         }
+    });
+    Ok(model.unwrap_or_else(|| DEFAULT_MODEL.to_string()))
+}
+
+fn parse_prompt_wrapper(template: &str) -> Result<String, String> {
+    if template.contains(PROMPT_PLACEHOLDER) {
+        Ok(template.to_string())
+    } else {
+        Err(format!(
+            "The prompt wrapper must contain {} where the prompt goes, got '{}'",
+            PROMPT_PLACEHOLDER, template
+        ))
     }
+}
 
-    Ok(())
+fn parse_history_limit(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(limit) if limit > 0 => Ok(limit),
+        _ => Err(format!(
+            "history-limit must be a positive number of turns, got '{}'",
+            value
+        )),
+    }
+}
+
+fn parse_thinking_budget(value: &str) -> Result<i32, String> {
+    match value.parse::<i32>() {
+        Ok(budget) if budget >= -1 => Ok(budget),
+        _ => Err(format!(
+            "thinking-budget must be a number of tokens, 0 or -1, got '{}'",
+            value
+        )),
+    }
+}
+
+/// Parses `--gen-config` JSON, returning the config and the keys it didn't
+/// recognize so they can be reported rather than silently sent.
+fn parse_gen_config(value: &str) -> Result<(GenerationConfig, Vec<String>), String> {
+    let mut fields: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(value).map_err(|e| format!("Expected a JSON object: {}", e))?;
+    let unknown: Vec<String> = fields
+        .keys()
+        .filter(|key| !GENERATION_CONFIG_FIELDS.contains(&key.as_str()))
+        .cloned()
+        .collect();
+    fields.retain(|key, _| GENERATION_CONFIG_FIELDS.contains(&key.as_str()));
+    let config: GenerationConfig =
+        serde_json::from_value(serde_json::Value::Object(fields)).map_err(|e| e.to_string())?;
+    // Hold the JSON to the same limits as the individual flags.
+    if let Some(temperature) = config.temperature {
+        parse_temperature(&temperature.to_string())?;
+    }
+    if let Some(top_k) = config.top_k {
+        parse_top_k(&top_k.to_string())?;
+    }
+    if let Some(budget) = config.thinking_budget() {
+        parse_thinking_budget(&budget.to_string())?;
+    }
+    if let Some(count) = config.candidate_count {
+        parse_candidate_count(&count.to_string())?;
+    }
+    for penalty in [config.presence_penalty, config.frequency_penalty]
+        .into_iter()
+        .flatten()
+    {
+        parse_penalty(&penalty.to_string())?;
+    }
+    Ok((config, unknown))
+}
+
+fn optional_label<T: std::fmt::Display>(value: Option<T>) -> String {
+    value.map_or_else(|| "default".to_string(), |value| value.to_string())
+}
+
+fn switch_label(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiRequest<'a> {
+    contents: &'a [Content],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<SystemInstruction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generation_config: Option<GenerationConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cached_content: Option<&'a str>,
+}
+/// Sampling parameters sent as `generationConfig`. Unset fields are left out
+/// of the request so the model's own defaults apply.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+struct GenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking_config: Option<ThinkingConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    candidate_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_logprobs: Option<bool>,
+}
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+struct ThinkingConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking_budget: Option<i32>,
+    /// Ask for summaries of the model's thinking as parts marked `thought`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    include_thoughts: bool,
+}
+
+impl ThinkingConfig {
+    fn budget(thinking_budget: i32) -> Self {
+        ThinkingConfig {
+            thinking_budget: Some(thinking_budget),
+            include_thoughts: false,
+        }
+    }
+}
+
+impl GenerationConfig {
+    fn is_empty(&self) -> bool {
+        self.temperature.is_none()
+            && self.top_k.is_none()
+            && self.seed.is_none()
+            && self.thinking_config.is_none()
+            && self.candidate_count.is_none()
+            && self.presence_penalty.is_none()
+            && self.frequency_penalty.is_none()
+            && self.response_logprobs.is_none()
+    }
+
+    /// Fills in any field left unset here from `fallback`.
+    fn or(self, fallback: GenerationConfig) -> GenerationConfig {
+        GenerationConfig {
+            temperature: self.temperature.or(fallback.temperature),
+            top_k: self.top_k.or(fallback.top_k),
+            seed: self.seed.or(fallback.seed),
+            thinking_config: self.thinking_config.or(fallback.thinking_config),
+            candidate_count: self.candidate_count.or(fallback.candidate_count),
+            presence_penalty: self.presence_penalty.or(fallback.presence_penalty),
+            frequency_penalty: self.frequency_penalty.or(fallback.frequency_penalty),
+            response_logprobs: self.response_logprobs.or(fallback.response_logprobs),
+        }
+    }
+
+    fn thinking_budget(&self) -> Option<i32> {
+        self.thinking_config
+            .and_then(|config| config.thinking_budget)
+    }
+}
+#[derive(Serialize)]
+struct SystemInstruction {
+    parts: Vec<Part>,
+}
+
+impl SystemInstruction {
+    fn new(text: &str) -> Self {
+        SystemInstruction {
+            parts: vec![Part::text(text)],
+        }
+    }
+}
+/// Who a turn came from. The API only accepts `user` and `model` turns in
+/// `contents`; `system` turns only appear in saved sessions and carry the
+/// system instruction.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Role {
+    User,
+    Model,
+    System,
+}
+#[derive(Serialize, Deserialize, Clone)]
+struct Content {
+    role: Role,
+    parts: Vec<Part>,
+    /// Entered with `secret`: sent to the model but never saved or shown.
+    #[serde(skip)]
+    redacted: bool,
+    /// Kept in saved sessions next to the turn, but never sent.
+    #[serde(skip)]
+    info: TurnInfo,
+}
+
+/// When a turn happened and, for model turns, what produced it and how long
+/// it took, for `replay` and for looking back at a saved session.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct TurnInfo {
+    /// RFC 3339 local time the turn was sent or received.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generation_config: Option<GenerationConfig>,
+}
+/// A piece of a turn: text, or a file uploaded with `attach`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Part {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_data: Option<FileData>,
+}
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct FileData {
+    mime_type: String,
+    file_uri: String,
+}
+
+impl Part {
+    fn text(text: &str) -> Self {
+        Part {
+            text: text.to_string(),
+            file_data: None,
+        }
+    }
+}
+
+impl Content {
+    fn new(role: Role, text: &str) -> Self {
+        Content {
+            role,
+            parts: vec![Part::text(text)],
+            redacted: false,
+            info: TurnInfo::default(),
+        }
+    }
+
+    /// A model turn for `text`, which `settings` produced in `latency`.
+    fn reply(text: &str, settings: &Settings, latency: Duration) -> Self {
+        let generation = settings.generation_config();
+        Content {
+            info: TurnInfo {
+                latency_ms: Some(latency.as_millis() as u64),
+                model: Some(settings.model.clone()),
+                generation_config: (!generation.is_empty()).then_some(generation),
+                ..TurnInfo::default()
+            },
+            ..Content::new(Role::Model, text)
+        }
+        .stamped()
+    }
+
+    /// This turn with the current time as its timestamp.
+    fn stamped(mut self) -> Self {
+        self.info.timestamp = Some(Local::now().to_rfc3339());
+        self
+    }
+
+    /// A user turn that is left out of saved sessions and `history`.
+    fn redacted(text: &str) -> Self {
+        Content {
+            redacted: true,
+            ..Content::new(Role::User, text)
+        }
+    }
+
+    /// A user turn with `attachments` ahead of the prompt text.
+    fn with_attachments(attachments: Vec<Part>, text: &str) -> Self {
+        Content {
+            role: Role::User,
+            parts: attachments.into_iter().chain([Part::text(text)]).collect(),
+            redacted: false,
+            info: TurnInfo::default(),
+        }
+    }
+
+    /// The parts queued ahead of the prompt in this turn, to queue again if
+    /// it couldn't be sent.
+    fn into_attachments(mut self) -> impl Iterator<Item = Part> {
+        self.parts.pop();
+        self.parts.into_iter()
+    }
+
+    fn text(&self) -> String {
+        self.parts.iter().map(|part| part.text.as_str()).collect()
+    }
+}
+
+/// The chat so far: an optional system instruction plus the user and model turns.
+#[derive(Default)]
+struct Conversation {
+    system_instruction: Option<String>,
+    turns: Vec<Content>,
+    cache: Option<CachedContext>,
+    /// Whether there are turns that haven't been saved with `save`.
+    unsaved: bool,
+}
+
+/// A conversation in the background, with the settings and forks it had,
+/// until `session switch` brings it back.
+struct NamedSession {
+    name: String,
+    /// Color of the name in the prompt, so sessions are easy to tell apart.
+    color: &'static str,
+    conversation: Conversation,
+    settings: Settings,
+    forks: Vec<(Vec<Content>, bool)>,
+}
+
+/// Name of the session the REPL starts in.
+const FIRST_SESSION_NAME: &str = "main";
+
+/// Colors given to sessions in the order they are created.
+fn session_colors() -> [&'static str; 4] {
+    let t = theme();
+    [t.cyan, t.green, t.yellow, t.blue]
+}
+
+/// Returns at most the last `limit` turns, starting at a user turn so the
+/// window never opens with a dangling model reply.
+fn recent_turns(turns: &[Content], limit: Option<usize>) -> &[Content] {
+    let Some(limit) = limit else {
+        return turns;
+    };
+    let mut window = &turns[turns.len().saturating_sub(limit)..];
+    if window.len() > 1 && window[0].role == Role::Model {
+        window = &window[1..];
+    }
+    window
+}
+
+/// A context uploaded with `cache-context`, which only applies to the model
+/// it was created for.
+struct CachedContext {
+    name: String,
+    model: String,
+}
+
+impl Conversation {
+    fn request<'a>(&'a self, settings: &'a Settings) -> GeminiRequest<'a> {
+        let generation = settings.generation_config();
+        let cache = self
+            .cache
+            .as_ref()
+            .filter(|cache| cache.model == settings.model);
+        GeminiRequest {
+            generation_config: (!generation.is_empty()).then_some(generation),
+            contents: recent_turns(&self.turns, settings.history_limit),
+            // The cache already carries the system instruction, and the API
+            // rejects requests that set both.
+            system_instruction: match cache {
+                Some(_) => None,
+                None => self
+                    .system_instruction
+                    .as_deref()
+                    .map(SystemInstruction::new),
+            },
+            cached_content: cache.map(|cache| cache.name.as_str()),
+        }
+    }
+
+    /// Flattens the conversation for saving, with the system instruction as a
+    /// leading `system` turn.
+    fn to_contents(&self) -> Vec<Content> {
+        let system = self
+            .system_instruction
+            .as_deref()
+            .map(|text| Content::new(Role::System, text));
+        let turns = self.turns.iter().map(|turn| {
+            if turn.redacted {
+                Content {
+                    info: turn.info.clone(),
+                    ..Content::new(turn.role, REDACTED_TEXT)
+                }
+            } else {
+                turn.clone()
+            }
+        });
+        system.into_iter().chain(turns).collect()
+    }
+
+    /// Rebuilds a conversation from saved contents. `system` turns become the
+    /// system instruction rather than chat turns.
+    fn from_contents(contents: Vec<Content>) -> Self {
+        let mut conversation = Conversation::default();
+        for content in contents {
+            match content.role {
+                Role::System => conversation.system_instruction = Some(content.text()),
+                Role::User | Role::Model => conversation.turns.push(content),
+            }
+        }
+        conversation
+    }
+}
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiResponse {
+    #[serde(default)]
+    candidates: Vec<Candidate>,
+    usage_metadata: Option<UsageMetadata>,
+}
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Candidate {
+    // The last streamed chunk may carry only the finish reason.
+    #[serde(default)]
+    content: ResponseContent,
+    finish_reason: Option<String>,
+    /// Average log probability of the candidate's tokens, when the API reports it.
+    avg_logprobs: Option<f64>,
+    /// The chosen tokens and their log probabilities, when asked for with `responseLogprobs`.
+    logprobs_result: Option<LogprobsResult>,
+}
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LogprobsResult {
+    #[serde(default)]
+    chosen_candidates: Vec<TokenLogprob>,
+}
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct TokenLogprob {
+    #[serde(default)]
+    token: String,
+    #[serde(default)]
+    log_probability: f64,
+}
+#[derive(Deserialize, Default)]
+struct ResponseContent {
+    #[serde(default)]
+    parts: Vec<ResponsePart>,
+}
+#[derive(Deserialize)]
+struct ResponsePart {
+    #[serde(default)]
+    text: String,
+    /// A summary of the model's thinking rather than part of the answer.
+    #[serde(default)]
+    thought: bool,
+}
+/// Token counts for a request and its response.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    #[serde(default)]
+    prompt_token_count: u32,
+    #[serde(default)]
+    candidates_token_count: u32,
+    #[serde(default)]
+    total_token_count: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CountTokensResponse {
+    total_tokens: u32,
+}
+
+/// Parses a `--header "Key: Value"` argument.
+fn parse_header(arg: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = arg
+        .split_once(':')
+        .ok_or_else(|| format!("expected 'Key: Value', got '{}'", arg))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("'{}' is not a valid header name", name.trim()))?;
+    if PROTECTED_HEADERS.contains(&name.as_str()) {
+        return Err(format!("the {} header can't be overridden", name));
+    }
+    let value = HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("invalid value for header {}", name))?;
+    Ok((name, value))
+}
+
+fn model_url(model: &str, method: &str, api_key: &str) -> String {
+    format!(
+        "{}/models/{}:{}?key={}",
+        api_base_url(),
+        model,
+        method,
+        api_key
+    )
+}
+
+/// Checks that `name` looks like a Gemini API model name, to catch typos
+/// before they turn into confusing 404s from the API.
+fn validate_model_name(name: &str) -> Result<(), String> {
+    let known_family = ["gemini-", "gemma-", "learnlm-"]
+        .iter()
+        .any(|family| name.starts_with(family));
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.');
+    if known_family && valid_chars {
+        Ok(())
+    } else {
+        Err(format!(
+            "'{}' is not a valid model name (expected something like '{}')",
+            name, DEFAULT_MODEL
+        ))
+    }
+}
+
+/// Splits an `@model:<name> <prompt>` prefix off `input`, returning the model
+/// name and the remaining prompt.
+fn parse_model_override(input: &str) -> Option<(&str, &str)> {
+    let rest = input.strip_prefix(MODEL_OVERRIDE_PREFIX)?;
+    match rest.split_once(char::is_whitespace) {
+        Some((model, prompt)) => Some((model, prompt.trim_start())),
+        None => Some((rest, "")),
+    }
+}
+
+/// Returns the rough input cost in USD of `tokens` tokens, if the model's price is known.
+fn estimate_cost(model: &str, tokens: u32) -> Option<f64> {
+    INPUT_PRICING
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, per_million)| tokens as f64 * per_million / 1_000_000.0)
+}
+
+/// Returns the share of the model's context window that `tokens` tokens
+/// take up, if the model's limit is known.
+fn context_usage(model: &str, tokens: u32) -> Option<f64> {
+    CONTEXT_LIMITS
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, limit)| tokens as f64 / *limit as f64)
+}
+
+/// An error response from the Gemini API.
+#[derive(Debug)]
+struct ApiError {
+    status: u16,
+    message: String,
+    reason: Option<String>,
+    /// The quotas a 429 says were exceeded, e.g. `GenerateRequestsPerDayPerProjectPerModel-FreeTier`.
+    quota_ids: Vec<String>,
+    /// How long a 429 says to wait before trying again.
+    retry_delay: Option<Duration>,
+}
+
+/// What a 429 means for retrying.
+#[derive(Debug, PartialEq)]
+enum RateLimit {
+    /// A per-minute limit: trying again after a short wait should work.
+    Temporary(Option<Duration>),
+    /// A daily quota, or no quota at all without billing: retrying won't help
+    /// until it resets.
+    Exhausted,
+}
+
+impl ApiError {
+    fn rate_limit(&self) -> Option<RateLimit> {
+        if self.status != 429 {
+            return None;
+        }
+        let message = self.message.to_lowercase();
+        let exhausted = self.quota_ids.iter().any(|id| id.contains("PerDay"))
+            || message.contains("per day")
+            || message.contains("daily")
+            || message.contains("billing");
+        Some(if exhausted {
+            RateLimit::Exhausted
+        } else {
+            RateLimit::Temporary(self.retry_delay)
+        })
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.reason.as_deref() == Some("API_KEY_INVALID") {
+            return write!(
+                f,
+                "Your API key appears to be invalid. Check the value of {}.",
+                API_KEY_SOURCE.get().map_or(API_KEY_ENV, String::as_str)
+            );
+        }
+        if self.rate_limit() == Some(RateLimit::Exhausted) {
+            return write!(
+                f,
+                "The API quota for this key is used up, so retrying won't help until it resets. \
+                 Wait for the daily reset, or switch to another API key or model. ({})",
+                self.message
+            );
+        }
+        write!(f, "API Error ({}): {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[derive(Deserialize)]
+struct ApiErrorBody {
+    error: ApiErrorDetail,
+}
+#[derive(Deserialize)]
+struct ApiErrorDetail {
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    details: Vec<ApiErrorInfo>,
+}
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiErrorInfo {
+    reason: Option<String>,
+    /// From a `QuotaFailure` detail.
+    #[serde(default)]
+    violations: Vec<QuotaViolation>,
+    /// From a `RetryInfo` detail, e.g. "23s".
+    retry_delay: Option<String>,
+}
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QuotaViolation {
+    quota_id: Option<String>,
+}
+
+/// Builds the `ApiError` for a failed response from its status and body,
+/// keeping just the message and details from a JSON error body when there is one.
+fn api_error(status: u16, body: String) -> ApiError {
+    let Ok(parsed) = serde_json::from_str::<ApiErrorBody>(&body) else {
+        return ApiError {
+            status,
+            message: body,
+            reason: None,
+            quota_ids: Vec::new(),
+            retry_delay: None,
+        };
+    };
+    let details = parsed.error.details;
+    ApiError {
+        status,
+        message: parsed.error.message,
+        reason: details.iter().find_map(|detail| detail.reason.clone()),
+        quota_ids: details
+            .iter()
+            .flat_map(|detail| &detail.violations)
+            .filter_map(|violation| violation.quota_id.clone())
+            .collect(),
+        retry_delay: details
+            .iter()
+            .filter_map(|detail| detail.retry_delay.as_deref())
+            .find_map(|delay| delay.strip_suffix('s')?.parse::<f64>().ok())
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok()),
+    }
+}
+
+/// Turns a non-success HTTP response into an `ApiError`.
+async fn check_status(
+    response: reqwest::Response,
+) -> Result<reqwest::Response, Box<dyn std::error::Error>> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await?;
+    Err(api_error(status.as_u16(), body).into())
+}
+
+async fn count_tokens(
+    client: &Client,
+    api_key: &str,
+    model: &str,
+    contents: &[Content],
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let url = model_url(model, "countTokens", api_key);
+    let request = GeminiRequest {
+        contents,
+        system_instruction: None,
+        generation_config: None,
+        cached_content: None,
+    };
+    let response = client.post(&url).json(&request).send().await?;
+    let count: CountTokensResponse = check_status(response).await?.json().await?;
+    Ok(count.total_tokens)
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateCacheRequest<'a> {
+    model: String,
+    contents: [Content; 1],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<SystemInstruction>,
+    ttl: &'a str,
+}
+#[derive(Deserialize)]
+struct CachedContentResponse {
+    name: String,
+}
+
+/// Uploads `text` as a cached context for `model`, returning the cache's name.
+async fn create_cache(
+    client: &Client,
+    api_key: &str,
+    model: &str,
+    text: &str,
+    system_instruction: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let url = format!("{}/cachedContents?key={}", api_base_url(), api_key);
+    let request = CreateCacheRequest {
+        model: format!("models/{}", model),
+        contents: [Content::new(Role::User, text)],
+        system_instruction: system_instruction.map(SystemInstruction::new),
+        ttl: CACHE_TTL,
+    };
+    let response = client.post(&url).json(&request).send().await?;
+    let cache: CachedContentResponse = check_status(response).await?.json().await?;
+    Ok(cache.name)
+}
+
+async fn delete_cache(
+    client: &Client,
+    api_key: &str,
+    name: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let url = format!("{}/{}?key={}", api_base_url(), name, api_key);
+    check_status(client.delete(&url).send().await?).await?;
+    Ok(())
+}
+
+/// Whether `error` says the cached context is gone, usually because its TTL ran out.
+fn is_cache_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    error.downcast_ref::<ApiError>().is_some_and(|error| {
+        let message = error.message.to_lowercase();
+        message.contains("cachedcontent") || message.contains("cached content")
+    })
+}
+
+#[derive(Deserialize)]
+struct UploadResponse {
+    file: UploadedFile,
+}
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadedFile {
+    uri: String,
+    mime_type: String,
+}
+
+/// The Files API upload endpoint: the API base URL with `/upload` in front
+/// of its path, e.g. `https://host/upload/v1beta/files`.
+fn upload_url(api_key: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut url = reqwest::Url::parse(api_base_url())?;
+    let path = format!("/upload{}/files", url.path().trim_end_matches('/'));
+    url.set_path(&path);
+    url.set_query(Some(&format!("key={}", api_key)));
+    Ok(url.into())
+}
+
+/// Picks the MIME type for an attachment. PDFs and UTF-8 text are supported.
+fn attachment_mime_type(path: &Path, bytes: &[u8]) -> Result<&'static str, String> {
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    if extension.eq_ignore_ascii_case("pdf") {
+        Ok("application/pdf")
+    } else if std::str::from_utf8(bytes).is_ok() {
+        Ok("text/plain")
+    } else {
+        Err(format!(
+            "Can't attach {}: only PDF and text files are supported",
+            path.display()
+        ))
+    }
+}
+
+/// Uploads a file with the Files API's resumable protocol, in chunks so
+/// progress can be shown, and returns the part that refers to it.
+async fn upload_file(
+    client: &Client,
+    api_key: &str,
+    path: &Path,
+    quiet: bool,
+) -> Result<Part, Box<dyn std::error::Error>> {
+    let t = theme();
+    let bytes = fs::read(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let mime_type = attachment_mime_type(path, &bytes)?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let response = client
+        .post(upload_url(api_key)?)
+        .header("X-Goog-Upload-Protocol", "resumable")
+        .header("X-Goog-Upload-Command", "start")
+        .header("X-Goog-Upload-Header-Content-Length", bytes.len())
+        .header("X-Goog-Upload-Header-Content-Type", mime_type)
+        .json(&serde_json::json!({ "file": { "display_name": name } }))
+        .send()
+        .await?;
+    let response = check_status(response).await?;
+    let session_url = response
+        .headers()
+        .get("x-goog-upload-url")
+        .and_then(|url| url.to_str().ok())
+        .ok_or("The upload response had no upload URL")?
+        .to_string();
+
+    let mut offset = 0;
+    let mut uploaded = None;
+    for chunk in bytes.chunks(UPLOAD_CHUNK_BYTES) {
+        let last = offset + chunk.len() == bytes.len();
+        let command = if last { "upload, finalize" } else { "upload" };
+        let response = client
+            .post(&session_url)
+            .header("X-Goog-Upload-Offset", offset)
+            .header("X-Goog-Upload-Command", command)
+            .body(chunk.to_vec())
+            .send()
+            .await?;
+        let response = check_status(response).await?;
+        offset += chunk.len();
+        if !quiet {
+            print!(
+                "\r{}Uploading {}... {}%{}",
+                t.dim,
+                name,
+                offset * 100 / bytes.len(),
+                t.reset
+            );
+            io::stdout().flush().unwrap();
+        }
+        if last {
+            uploaded = Some(response.json::<UploadResponse>().await?.file);
+        }
+    }
+    if !quiet {
+        println!();
+    }
+    let file = uploaded.ok_or_else(|| format!("{} is empty", path.display()))?;
+    Ok(Part {
+        text: String::new(),
+        file_data: Some(FileData {
+            mime_type: file.mime_type,
+            file_uri: file.uri,
+        }),
+    })
+}
+
+#[derive(Deserialize)]
+struct ModelList {
+    #[serde(default)]
+    models: Vec<ModelInfo>,
+}
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ModelInfo {
+    name: String,
+    #[serde(default)]
+    supported_generation_methods: Vec<String>,
+}
+
+/// Lists the models that can generate content, for completing model names.
+/// Run at startup, it also finds a bad API key or missing connection before
+/// the first prompt.
+async fn list_models(
+    client: &Client,
+    api_key: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let url = format!("{}/models?pageSize=1000&key={}", api_base_url(), api_key);
+    let list: ModelList = check_status(client.get(&url).send().await?)
+        .await?
+        .json()
+        .await?;
+    Ok(list
+        .models
+        .into_iter()
+        .filter(|model| {
+            model
+                .supported_generation_methods
+                .iter()
+                .any(|method| method == "generateContent")
+        })
+        .map(|model| {
+            model
+                .name
+                .strip_prefix("models/")
+                .unwrap_or(&model.name)
+                .to_string()
+        })
+        .collect())
+}
+
+impl GeminiResponse {
+    /// Moves the candidate `selection` picks to the front, where `Reply`
+    /// reads it. Without that many candidates the first one stays.
+    fn select_candidate(&mut self, selection: CandidateSelection) {
+        let chosen = match selection {
+            CandidateSelection::Number(number) => number - 1,
+            CandidateSelection::Best => best_candidate(&self.candidates),
+        };
+        if chosen < self.candidates.len() {
+            self.candidates.swap(0, chosen);
+        }
+    }
+}
+
+/// The index of the candidate the model found most probable, by average log
+/// probability. When any candidate lacks one, the longest is taken instead.
+fn best_candidate(candidates: &[Candidate]) -> usize {
+    let scores: Option<Vec<f64>> = candidates
+        .iter()
+        .map(|candidate| candidate.avg_logprobs)
+        .collect();
+    let scores = scores.unwrap_or_else(|| {
+        candidates
+            .iter()
+            .map(|candidate| {
+                let parts = &candidate.content.parts;
+                parts
+                    .iter()
+                    .filter(|part| !part.thought)
+                    .map(|part| part.text.chars().count())
+                    .sum::<usize>() as f64
+            })
+            .collect()
+    });
+    // The first of equals wins, so ties keep the API's order.
+    scores
+        .iter()
+        .enumerate()
+        .rev()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map_or(0, |(index, _)| index)
+}
+
+/// A complete response: its text, why the model stopped, and what it cost.
+#[derive(Default)]
+struct Reply {
+    text: String,
+    /// The model's summary of its thinking, kept apart from the answer.
+    thoughts: String,
+    finish_reason: Option<String>,
+    usage: Option<UsageMetadata>,
+    /// The candidate number asked for and how many came back, when there
+    /// weren't enough and the first is shown instead.
+    missing_candidate: Option<(usize, usize)>,
+    /// The tokens of the response with their log probabilities, with `logprobs` on.
+    tokens: Vec<TokenLogprob>,
+}
+
+impl Reply {
+    /// Adds a response, or one streamed chunk of it, returning its parts with
+    /// text. Later chunks carry the final finish reason and usage, so those
+    /// replace earlier values.
+    fn add<'a>(&mut self, response: &'a GeminiResponse) -> Vec<&'a ResponsePart> {
+        if let Some(reason) = response
+            .candidates
+            .first()
+            .and_then(|candidate| candidate.finish_reason.clone())
+        {
+            self.finish_reason = Some(reason);
+        }
+        if response.usage_metadata.is_some() {
+            self.usage = response.usage_metadata;
+        }
+        let Some(candidate) = response.candidates.first() else {
+            return Vec::new();
+        };
+        if let Some(logprobs) = &candidate.logprobs_result {
+            self.tokens
+                .extend(logprobs.chosen_candidates.iter().cloned());
+        }
+        let parts: Vec<&ResponsePart> = candidate
+            .content
+            .parts
+            .iter()
+            .filter(|part| !part.text.is_empty())
+            .collect();
+        for part in &parts {
+            match part.thought {
+                true => self.thoughts.push_str(&part.text),
+                false => self.text.push_str(&part.text),
+            }
+        }
+        parts
+    }
+
+    /// Fails when there is no text, with an `EmptyResponse` error.
+    fn into_result(self) -> Result<Reply, Box<dyn std::error::Error>> {
+        if !self.text.is_empty() {
+            return Ok(self);
+        }
+        Err(Box::new(EmptyResponse {
+            finish_reason: self.finish_reason,
+        }))
+    }
+
+    /// Warns when the response was cut short, and prints its token counts if
+    /// `usage` is on.
+    fn report(&self, settings: &Settings) {
+        let t = theme();
+        match self.finish_reason.as_deref() {
+            None | Some("STOP") => {}
+            Some("MAX_TOKENS") => eprintln!(
+                "{}Warning:{} The response was cut off at the output token limit.",
+                t.yellow, t.reset
+            ),
+            Some(reason) => eprintln!(
+                "{}Warning:{} The response ended early (finish reason {}).",
+                t.yellow, t.reset, reason
+            ),
+        }
+        if let Some((number, count)) = self.missing_candidate {
+            eprintln!(
+                "{}Warning:{} Candidate {} was asked for but only {} came back; this is the first.",
+                t.yellow, t.reset, number, count
+            );
+        }
+        if settings.logprobs {
+            let low = uncertain_spans(&self.text, &self.tokens).len();
+            match self.tokens.len() {
+                0 => println!(
+                    "{}No log probabilities came back for this response.{}",
+                    t.dim, t.reset
+                ),
+                count => println!(
+                    "{}{} of {} tokens had under {:.0}% probability and are underlined.{}",
+                    t.dim,
+                    low,
+                    count,
+                    LOW_CONFIDENCE_PROBABILITY * 100.0,
+                    t.reset
+                ),
+            }
+        }
+        if let (true, Some(usage)) = (settings.usage, self.usage) {
+            println!(
+                "{}Tokens: {} prompt, {} response, {} total{}",
+                t.dim,
+                usage.prompt_token_count,
+                usage.candidates_token_count,
+                usage.total_token_count,
+                t.reset
+            );
+        }
+        // The next request resends all of this, so it is what the next turn starts from.
+        if let Some(usage) = self.usage {
+            match context_usage(&settings.model, usage.total_token_count) {
+                Some(share) if share >= CONTEXT_WARNING_THRESHOLD => println!(
+                    "{}The conversation fills {:.0}% of the context window; 'summarize' or 'reset' makes room.{}",
+                    t.dim,
+                    share * 100.0,
+                    t.reset
+                ),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// A response without any text, and why the model stopped if it said.
+#[derive(Debug)]
+struct EmptyResponse {
+    finish_reason: Option<String>,
+}
+
+impl std::fmt::Display for EmptyResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.finish_reason.as_deref() {
+            Some(reason) if reason != "STOP" => {
+                write!(f, "No response content found (finish reason {})", reason)
+            }
+            _ => write!(f, "No response content found"),
+        }
+    }
+}
+
+impl std::error::Error for EmptyResponse {}
+
+/// Whether `error` is a transport failure that may well not happen again:
+/// failing to connect (including DNS and TLS), a timeout, or the connection
+/// dropping while sending. Errors building the request, such as a malformed
+/// URL, would only repeat.
+fn is_transient_network_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    error
+        .downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| !e.is_builder() && (e.is_timeout() || e.is_connect() || e.is_request()))
+}
+
+/// How long to wait before retrying a request that hit a per-minute rate
+/// limit, or `None` when it shouldn't be retried: the quota is used up, or
+/// the API asks for a longer wait than `MAX_RATE_LIMIT_WAIT`. Without a
+/// wait from the API, the network backoff for `attempt` is used.
+fn rate_limit_wait(
+    error: &(dyn std::error::Error + 'static),
+    attempt: u32,
+    jitter: Jitter,
+) -> Option<Duration> {
+    match error.downcast_ref::<ApiError>()?.rate_limit()? {
+        RateLimit::Exhausted => None,
+        RateLimit::Temporary(Some(delay)) => (delay <= MAX_RATE_LIMIT_WAIT).then_some(delay),
+        RateLimit::Temporary(None) => Some(network_retry_delay(attempt, jitter)),
+    }
+}
+
+/// How long to wait before network retry number `attempt`, counting from 0:
+/// a backoff that doubles each time, randomized by `jitter`.
+fn network_retry_delay(attempt: u32, jitter: Jitter) -> Duration {
+    let backoff = NETWORK_RETRY_DELAY * 2u32.saturating_pow(attempt);
+    jittered(backoff, jitter, random_fraction())
+}
+
+/// Applies `jitter` to `backoff`, with `fraction` from 0 to 1 as the random part.
+fn jittered(backoff: Duration, jitter: Jitter, fraction: f64) -> Duration {
+    match jitter {
+        Jitter::Full => backoff.mul_f64(fraction),
+        Jitter::Equal => backoff / 2 + (backoff / 2).mul_f64(fraction),
+        Jitter::None => backoff,
+    }
+}
+
+/// A number from 0 up to 1 that differs between calls and processes, which
+/// is all jitter needs. std's randomly keyed hasher stands in for a random
+/// number generator.
+fn random_fraction() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    hasher.write_u128(now.as_nanos());
+    // The top 53 bits fill an f64's mantissa exactly.
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Whether `error` is an empty response with no finish reason to explain it,
+/// which is worth retrying. Safety blocks and token limits would only repeat.
+fn is_unexplained_empty(error: &(dyn std::error::Error + 'static)) -> bool {
+    error
+        .downcast_ref::<EmptyResponse>()
+        .is_some_and(|empty| matches!(empty.finish_reason.as_deref(), None | Some("STOP")))
+}
+
+async fn send_to_gemini(
+    client: &Client,
+    api_key: &str,
+    model: &str,
+    request: &GeminiRequest<'_>,
+    selection: CandidateSelection,
+) -> Result<Reply, Box<dyn std::error::Error>> {
+    let url = model_url(model, "generateContent", api_key);
+    let response = client.post(&url).json(request).send().await?;
+    let response = check_status(response).await?;
+    let mut gemini_response: GeminiResponse = response.json().await?;
+    let mut reply = Reply::default();
+    if let CandidateSelection::Number(number) = selection {
+        let count = gemini_response.candidates.len();
+        if number > count && count > 0 {
+            reply.missing_candidate = Some((number, count));
+        }
+    }
+    gemini_response.select_candidate(selection);
+    reply.add(&gemini_response);
+    reply.into_result()
+}
+
+/// Posts a hand-written `generateContent` body without touching it, for trying
+/// API fields this client doesn't model yet.
+async fn send_raw_request(
+    client: &Client,
+    api_key: &str,
+    model: &str,
+    body: String,
+) -> Result<Reply, Box<dyn std::error::Error>> {
+    // Catch malformed files locally rather than with a vague 400.
+    serde_json::from_str::<serde_json::Value>(&body)
+        .map_err(|e| format!("Request body is not valid JSON: {}", e))?;
+    let url = model_url(model, "generateContent", api_key);
+    let response = client
+        .post(&url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await?;
+    let gemini_response: GeminiResponse = check_status(response).await?.json().await?;
+    let mut reply = Reply::default();
+    reply.add(&gemini_response);
+    reply.into_result()
+}
+
+/// What `stream_from_gemini` received.
+struct StreamedResponse {
+    reply: Reply,
+    /// The user stopped the response before it was complete.
+    stopped: bool,
+}
+
+/// Like `send_to_gemini`, but uses the server-sent events endpoint and calls
+/// `on_part` with each piece of the response, thoughts included, as it arrives. If `stop`
+/// completes first, the connection is dropped and the text so far is returned.
+async fn stream_from_gemini(
+    client: &Client,
+    api_key: &str,
+    model: &str,
+    request: &GeminiRequest<'_>,
+    mut on_part: impl FnMut(&ResponsePart),
+    stop: impl std::future::Future<Output = ()>,
+) -> Result<StreamedResponse, Box<dyn std::error::Error>> {
+    let url = format!(
+        "{}&alt=sse",
+        model_url(model, "streamGenerateContent", api_key)
+    );
+    let response = client.post(&url).json(request).send().await?;
+    let mut response = check_status(response).await?;
+
+    let mut events = StreamEvents::default();
+    tokio::pin!(stop);
+    loop {
+        let chunk = tokio::select! {
+            chunk = response.chunk() => chunk?,
+            () = &mut stop => {
+                if events.reply.text.is_empty() {
+                    return Err("Stopped before any response arrived".into());
+                }
+                return Ok(StreamedResponse { reply: events.reply, stopped: true });
+            }
+        };
+        let Some(chunk) = chunk else {
+            break;
+        };
+        events.push(&chunk, &mut on_part)?;
+    }
+    events.finish(&mut on_part)?;
+    Ok(StreamedResponse {
+        reply: events.reply.into_result()?,
+        stopped: false,
+    })
+}
+
+/// Collects a streamed response from its server-sent events. Chunks can end
+/// anywhere, even inside a UTF-8 character, so bytes are kept until their line
+/// is complete, and `data:` lines until the blank line that ends their event.
+#[derive(Default)]
+struct StreamEvents {
+    buffer: Vec<u8>,
+    /// The `data:` lines of the event being read, each followed by a newline.
+    data: String,
+    reply: Reply,
+}
+
+impl StreamEvents {
+    /// Adds a chunk of the body, calling `on_part` with the parts of each
+    /// event it completes.
+    fn push(
+        &mut self,
+        chunk: &[u8],
+        on_part: &mut impl FnMut(&ResponsePart),
+    ) -> serde_json::Result<()> {
+        self.buffer.extend_from_slice(chunk);
+        while let Some(newline) = self.buffer.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+            self.line(&String::from_utf8_lossy(&line), on_part)?;
+        }
+        Ok(())
+    }
+
+    /// Handles the end of the body, which may leave off the final line break
+    /// or blank line.
+    fn finish(&mut self, on_part: &mut impl FnMut(&ResponsePart)) -> serde_json::Result<()> {
+        let rest = std::mem::take(&mut self.buffer);
+        self.line(&String::from_utf8_lossy(&rest), on_part)?;
+        self.dispatch(on_part)
+    }
+
+    fn line(
+        &mut self,
+        line: &str,
+        on_part: &mut impl FnMut(&ResponsePart),
+    ) -> serde_json::Result<()> {
+        let line = line.trim_end_matches(['\n', '\r']);
+        if line.is_empty() {
+            return self.dispatch(on_part);
+        }
+        // Comments (`:`) and fields other than `data` carry nothing to show.
+        if let Some(data) = line.strip_prefix("data:") {
+            self.data.push_str(data.strip_prefix(' ').unwrap_or(data));
+            self.data.push('\n');
+        }
+        Ok(())
+    }
+
+    /// Parses the event whose `data:` lines have been collected, if any.
+    fn dispatch(&mut self, on_part: &mut impl FnMut(&ResponsePart)) -> serde_json::Result<()> {
+        let data = std::mem::take(&mut self.data);
+        if data.trim().is_empty() {
+            return Ok(());
+        }
+        let event: GeminiResponse = serde_json::from_str(&data)?;
+        for part in self.reply.add(&event) {
+            on_part(part);
+        }
+        Ok(())
+    }
+}
+
+/// Watches the terminal for `q` or Esc while a response streams in. The
+/// terminal is switched out of line-buffered mode so single keys arrive, and
+/// restored when this is dropped.
+#[cfg(unix)]
+struct StopKey {
+    original: libc::termios,
+    done: Arc<AtomicBool>,
+    pressed: Option<tokio::sync::oneshot::Receiver<()>>,
+    watcher: Option<std::thread::JoinHandle<()>>,
+}
+
+#[cfg(unix)]
+impl StopKey {
+    /// Starts watching, or returns `None` when stdin isn't a terminal.
+    fn watch() -> Option<StopKey> {
+        if !io::stdin().is_terminal() {
+            return None;
+        }
+        // SAFETY: termios is plain data, filled in by tcgetattr before use.
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return None;
+        }
+        let mut keys = original;
+        keys.c_lflag &= !(libc::ICANON | libc::ECHO);
+        keys.c_cc[libc::VMIN] = 1;
+        keys.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &keys) } != 0 {
+            return None;
+        }
+
+        let done = Arc::new(AtomicBool::new(false));
+        let (sender, pressed) = tokio::sync::oneshot::channel();
+        // Poll with a timeout rather than block in read, so the thread notices
+        // `done` and never swallows keys meant for the next prompt.
+        let watcher = std::thread::spawn({
+            let done = Arc::clone(&done);
+            move || {
+                while !done.load(Ordering::Relaxed) {
+                    let mut poll = libc::pollfd {
+                        fd: libc::STDIN_FILENO,
+                        events: libc::POLLIN,
+                        revents: 0,
+                    };
+                    if unsafe { libc::poll(&mut poll, 1, 100) } <= 0 {
+                        continue;
+                    }
+                    let mut key = 0u8;
+                    if unsafe { libc::read(libc::STDIN_FILENO, (&mut key as *mut u8).cast(), 1) }
+                        != 1
+                    {
+                        break;
+                    }
+                    if key == b'q' || key == 0x1b {
+                        let _ = sender.send(());
+                        break;
+                    }
+                }
+            }
+        });
+        Some(StopKey {
+            original,
+            done,
+            pressed: Some(pressed),
+            watcher: Some(watcher),
+        })
+    }
+
+    /// Completes when the user presses `q` or Esc.
+    async fn pressed(&mut self) {
+        if let Some(pressed) = self.pressed.take() {
+            if pressed.await.is_ok() {
+                return;
+            }
+        }
+        std::future::pending().await
+    }
+}
+
+#[cfg(unix)]
+impl Drop for StopKey {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+        if let Some(watcher) = self.watcher.take() {
+            let _ = watcher.join();
+        }
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// Stopping a response with a key needs terminal control that is only
+/// implemented for Unix.
+#[cfg(not(unix))]
+struct StopKey;
+
+#[cfg(not(unix))]
+impl StopKey {
+    fn watch() -> Option<StopKey> {
+        None
+    }
+
+    async fn pressed(&mut self) {
+        std::future::pending().await
+    }
+}
+
+/// Renders a streamed markdown response block by block. Text is held back
+/// until a block boundary (a blank line outside a code fence, followed by an
+/// unindented line), so paragraphs and fenced code blocks are always rendered
+/// whole while the trailing partial block waits for more input.
+#[derive(Default)]
+struct StreamRenderer {
+    pending: String,
+    /// Lines printed so far, for `line-numbers`.
+    lines: usize,
+    /// Prints the rendered blocks when `stream-delay` is set.
+    typewriter: Option<Typewriter>,
+    /// Whether the response's first text has arrived.
+    started: bool,
+}
+
+impl StreamRenderer {
+    fn new(settings: &Settings) -> StreamRenderer {
+        // Throttling output that nobody is watching would only slow it down.
+        let typewriter = (settings.stream_delay > 0 && io::stdout().is_terminal())
+            .then(|| Typewriter::start(Duration::from_millis(settings.stream_delay)));
+        StreamRenderer {
+            typewriter,
+            ..StreamRenderer::default()
+        }
+    }
+
+    fn push(&mut self, chunk: &str, settings: &Settings) {
+        self.pending.push_str(chunk);
+        // Only the response as a whole is trimmed, so blocks keep the blank
+        // lines that separate them.
+        if settings.trim && !self.started {
+            self.pending = trim_leading_blank_lines(&self.pending).to_string();
+            self.started = !self.pending.is_empty();
+        }
+        if let Some(boundary) = last_block_boundary(&self.pending) {
+            let rest = self.pending.split_off(boundary);
+            let options = RenderOptions {
+                trim: false,
+                ..settings.render_options()
+            };
+            let rendered = render_markdown(&self.pending, &options);
+            self.show(&rendered, settings);
+            self.pending = rest;
+        }
+    }
+
+    /// Renders what is left. A stopped response shows the text that arrived
+    /// without waiting for the typewriter to catch up.
+    fn finish(mut self, settings: &Settings, stopped: bool) {
+        if !self.pending.trim().is_empty() {
+            let rendered = render_markdown(&self.pending, &settings.render_options());
+            self.show(&rendered, settings);
+        }
+        if let Some(typewriter) = self.typewriter {
+            typewriter.finish(stopped);
+        }
+    }
+
+    fn show(&mut self, rendered: &str, settings: &Settings) {
+        match &self.typewriter {
+            Some(typewriter) => {
+                typewriter.write(number_lines(rendered, settings, &mut self.lines).into_owned())
+            }
+            None => print_rendered(rendered, settings, &mut self.lines),
+        }
+    }
+}
+
+/// Prints text a word at a time with a pause after each, for `stream-delay`.
+/// It runs on a thread of its own, so reading the response carries on at
+/// full speed while the display catches up.
+struct Typewriter {
+    sender: mpsc::Sender<String>,
+    /// Set to print whatever is still queued without pausing.
+    rush: Arc<AtomicBool>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+impl Typewriter {
+    fn start(delay: Duration) -> Typewriter {
+        let (sender, receiver) = mpsc::channel::<String>();
+        let rush = Arc::new(AtomicBool::new(false));
+        let rushing = rush.clone();
+        let thread = std::thread::spawn(move || {
+            for text in receiver {
+                for word in split_words(&text) {
+                    print!("{}", word);
+                    io::stdout().flush().unwrap();
+                    if !rushing.load(Ordering::Relaxed) {
+                        std::thread::sleep(delay);
+                    }
+                }
+            }
+        });
+        Typewriter {
+            sender,
+            rush,
+            thread,
+        }
+    }
+
+    fn write(&self, text: String) {
+        // The thread only stops once the sender is dropped, so this can't fail.
+        let _ = self.sender.send(text);
+    }
+
+    /// Waits until everything written has been printed, without further
+    /// pauses if `rush` is set.
+    fn finish(self, rush: bool) {
+        self.rush.store(rush, Ordering::Relaxed);
+        drop(self.sender);
+        let _ = self.thread.join();
+    }
+}
+
+/// Splits `text` into words, each with the whitespace that follows it, so
+/// that the pieces put back together are the text again.
+fn split_words(text: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut after_space = false;
+    for (i, c) in text.char_indices() {
+        if c.is_whitespace() {
+            after_space = true;
+        } else if after_space {
+            words.push(&text[start..i]);
+            start = i;
+            after_space = false;
+        }
+    }
+    if start < text.len() {
+        words.push(&text[start..]);
+    }
+    words
+}
+
+/// Returns the byte offset of the last point in `text` where the complete
+/// markdown blocks before it can be rendered without seeing what follows.
+fn last_block_boundary(text: &str) -> Option<usize> {
+    let mut boundary = None;
+    let mut in_fence = false;
+    let mut previous_blank = false;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        if !line.ends_with('\n') {
+            break; // Incomplete line, wait for the rest
+        }
+        let trimmed = line.trim();
+        if !in_fence && previous_blank && !trimmed.is_empty() && !line.starts_with([' ', '\t']) {
+            boundary = Some(offset);
+        }
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        }
+        previous_blank = trimmed.is_empty();
+        offset += line.len();
+    }
+    boundary
+}
+
+/// Renders a complete response, through the `--pipe` command if one is set.
+fn render_response(text: &str, settings: &Settings) {
+    match &settings.pipe {
+        Some(command) => render_piped(command, text, settings),
+        None => print_rendered(
+            &render_markdown(text, &settings.render_options()),
+            settings,
+            &mut 0,
+        ),
+    }
+}
+
+/// Renders a complete reply, underlining the tokens the model was unsure of
+/// when `logprobs` is on.
+fn render_reply(reply: &Reply, settings: &Settings) {
+    if !settings.logprobs || settings.pipe.is_some() || reply.tokens.is_empty() {
+        return render_response(&reply.text, settings);
+    }
+    let marked = mark_spans(&reply.text, &uncertain_spans(&reply.text, &reply.tokens));
+    let rendered = render_markdown(&marked, &settings.render_options());
+    print_rendered(&underline_marks(&rendered), settings, &mut 0);
+}
+
+/// Finds the tokens of `text` that were chosen with less than
+/// `LOW_CONFIDENCE_PROBABILITY`, as byte ranges without surrounding space.
+/// Tokens that could be markdown syntax, or that start a line, are left out,
+/// since marking them could change how the text is parsed.
+fn uncertain_spans(text: &str, tokens: &[TokenLogprob]) -> Vec<Range<usize>> {
+    let threshold = LOW_CONFIDENCE_PROBABILITY.ln();
+    let mut spans = Vec::new();
+    let mut position = 0;
+    for token in tokens {
+        if token.token.is_empty() {
+            continue;
+        }
+        // Tokens line up with the text, but thought tokens and partial
+        // characters don't appear in it; skip those rather than lose track.
+        let Some(start) = text[position..]
+            .find(&token.token)
+            .filter(|&offset| offset <= TOKEN_SEARCH_WINDOW)
+            .map(|offset| position + offset)
+        else {
+            continue;
+        };
+        position = start + token.token.len();
+        if token.log_probability >= threshold {
+            continue;
+        }
+        let core = token.token.trim();
+        let core_start = start + token.token.find(core).unwrap_or(0);
+        let line_start = text[..core_start].rfind('\n').map_or(0, |i| i + 1);
+        let plain = !core.is_empty()
+            && core
+                .chars()
+                .all(|c| c.is_alphanumeric() || matches!(c, '\'' | '.' | ',' | ';' | '?'));
+        if plain && !text[line_start..core_start].trim().is_empty() {
+            spans.push(core_start..core_start + core.len());
+        }
+    }
+    spans
+}
+
+/// Prints rendered output, numbering its lines after the `line` already
+/// printed when `line-numbers` is on.
+fn print_rendered(rendered: &str, settings: &Settings, line: &mut usize) {
+    print!("{}", number_lines(rendered, settings, line));
+    io::stdout().flush().unwrap();
+}
+
+/// Numbers the lines of rendered output after the `line` already printed
+/// when `line-numbers` is on, and leaves it as it is otherwise.
+fn number_lines<'a>(rendered: &'a str, settings: &Settings, line: &mut usize) -> Cow<'a, str> {
+    if !settings.line_numbers {
+        return Cow::Borrowed(rendered);
+    }
+    let t = theme();
+    let mut numbered = String::new();
+    for text in rendered.split_inclusive('\n') {
+        *line += 1;
+        write!(numbered, "{}{:>4}{} {}", t.dim, line, t.reset, text).unwrap();
+    }
+    Cow::Owned(numbered)
+}
+
+/// Prints `text` run through the shell `command`, falling back to the
+/// built-in renderer if the command can't be run or fails.
+fn render_piped(command: &str, text: &str, settings: &Settings) {
+    let t = theme();
+    match run_pipe(command, text) {
+        Ok(mut output) => {
+            if !output.ends_with('\n') {
+                output.push('\n');
+            }
+            print_rendered(&output, settings, &mut 0);
+        }
+        Err(e) => {
+            eprintln!(
+                "{}Warning:{} --pipe command '{}' failed: {}",
+                t.yellow, t.reset, command, e
+            );
+            print_rendered(
+                &render_markdown(text, &settings.render_options()),
+                settings,
+                &mut 0,
+            );
+        }
+    }
+}
+
+/// The part of `text` that `pattern` picks out for `--extract`: its first
+/// capture group, or the whole match when there is none or it didn't match.
+fn extract<'a>(pattern: &Regex, text: &'a str) -> Option<&'a str> {
+    let captures = pattern.captures(text)?;
+    let found = captures.get(1).or_else(|| captures.get(0))?;
+    Some(found.as_str())
+}
+
+/// Converts markdown to a standalone HTML page with `HTML_STYLESHEET`, for
+/// `--html`.
+fn render_html(text: &str) -> String {
+    let mut body = String::new();
+    pulldown_cmark::html::push_html(&mut body, Parser::new_ext(text, ParserOptions::all()));
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Gemini</title>\n<style>\n{}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+        HTML_STYLESHEET, body
+    )
+}
+
+/// Runs `command` for `run`, with `input` on stdin and the whole of
+/// `response` in the `GEMINI_RESPONSE` environment variable.
+fn run_with_response(
+    command: &str,
+    input: &str,
+    response: &str,
+) -> io::Result<std::process::Output> {
+    let mut command = shell_command(command);
+    command.env("GEMINI_RESPONSE", response);
+    run_with_input(command, input)
+}
+
+/// The code of `text` when it has exactly one fenced code block, which is
+/// what `run` feeds a command rather than the prose around it.
+fn single_code_block(text: &str) -> Option<String> {
+    match <[(String, String); 1]>::try_from(code_blocks(text)) {
+        Ok([(_, block)]) => Some(block),
+        Err(_) => None,
+    }
+}
+
+/// The fenced code blocks of a response in order, each with the language
+/// its fence names (empty when it names none).
+fn code_blocks(text: &str) -> Vec<(String, String)> {
+    let mut blocks = Vec::new();
+    let mut block: Option<(String, String)> = None;
+    for event in Parser::new_ext(text, ParserOptions::all()) {
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(language))) => {
+                block = Some((language.to_string(), String::new()))
+            }
+            Event::Text(text) => {
+                if let Some((_, code)) = &mut block {
+                    code.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => blocks.extend(block.take()),
+            _ => {}
+        }
+    }
+    blocks
+}
+
+/// The command in the arguments of `run`. `run -- <command>` always is one;
+/// otherwise the first word has to be a path or a program on `PATH` and the
+/// line can't end in a question mark, so "run me through how lifetimes work"
+/// goes to the model as a prompt.
+fn run_command(args: &str) -> Option<&str> {
+    if let Some(command) = args.strip_prefix("-- ") {
+        return Some(command.trim()).filter(|command| !command.is_empty());
+    }
+    let program = args.split_whitespace().next()?;
+    (!args.trim_end().ends_with('?') && (program.contains('/') || is_on_path(program)))
+        .then_some(args.trim())
+}
+
+/// Whether `program` names a file in one of the `PATH` directories.
+fn is_on_path(program: &str) -> bool {
+    let Some(path) = std::env::var_os("PATH") else {
+        return false;
+    };
+    std::env::split_paths(&path).any(|dir| {
+        let file = dir.join(program);
+        file.is_file() || (cfg!(windows) && file.with_extension("exe").is_file())
+    })
+}
+
+/// Splits the arguments of `write` into an optional block number and a path.
+/// Anything else, such as "a python script that parses csv", isn't the
+/// command but a prompt that starts with "write".
+fn parse_write_args(args: &str) -> Option<(Option<usize>, &str)> {
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [path] => Some((None, path)),
+        [number, path] => Some((Some(number.parse().ok()?), path)),
+        _ => None,
+    }
+}
+
+/// Where `write` saves a code block: `path`, with the extension of the
+/// block's language added when `path` has none.
+fn code_block_path(path: &str, language: &str) -> PathBuf {
+    let path = PathBuf::from(path);
+    // Only the language name counts, not attributes like `rust,ignore`.
+    let language = language.split([',', ' ']).next().unwrap_or_default();
+    match (path.extension(), language_extension(language)) {
+        (None, Some(extension)) if !language.is_empty() => path.with_extension(extension),
+        _ => path,
+    }
+}
+
+/// The destinations of the links in a response, in order and without repeats,
+/// for `links` and `open`.
+fn response_links(text: &str) -> Vec<String> {
+    let mut links: Vec<String> = Vec::new();
+    for event in Parser::new_ext(text, ParserOptions::all()) {
+        if let Event::Start(Tag::Link { dest_url, .. }) = event {
+            if !dest_url.is_empty() && !links.iter().any(|link| **link == *dest_url) {
+                links.push(dest_url.to_string());
+            }
+        }
+    }
+    links
+}
+
+/// The links in the most recent response.
+fn last_response_links(conversation: &Conversation) -> Vec<String> {
+    conversation
+        .turns
+        .iter()
+        .rev()
+        .find(|turn| turn.role == Role::Model)
+        .map(|turn| response_links(&turn.text()))
+        .unwrap_or_default()
+}
+
+/// Opens `url` with the platform's handler, which for web links is the
+/// default browser.
+fn open_in_browser(url: &str) -> Result<(), Box<dyn std::error::Error>> {
+    // The empty argument to `start` is the window title; without it a quoted
+    // URL would be taken for one.
+    let (program, args): (&str, &[&str]) = if cfg!(windows) {
+        ("cmd", &["/C", "start", ""])
+    } else if cfg!(target_os = "macos") {
+        ("open", &[])
+    } else {
+        ("xdg-open", &[])
+    };
+    let status = Command::new(program)
+        .args(args)
+        .arg(url)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_err(|e| format!("couldn't run {}: {}", program, e))?;
+    if !status.success() {
+        return Err(format!("the browser couldn't be opened ({})", status).into());
+    }
+    Ok(())
+}
+
+/// A `Command` that runs `command` with the platform's shell.
+fn shell_command(command: &str) -> Command {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut shell = Command::new(shell);
+    shell.args([flag, command]);
+    shell
+}
+
+/// Runs `command` with `input` on its stdin and collects its output. The
+/// input is written from a thread of its own, so a command that writes while
+/// it reads can't fill its stdout pipe and leave both sides waiting.
+fn run_with_input(mut command: Command, input: &str) -> io::Result<std::process::Output> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_owned();
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output()?;
+    match writer.join() {
+        // A command that exits without reading all of its input, like `head`,
+        // closes the pipe early; its exit status says more than this would.
+        Ok(Err(e)) if e.kind() != io::ErrorKind::BrokenPipe => Err(e),
+        Err(_) => Err(io::Error::other("writing the input panicked")),
+        Ok(_) => Ok(output),
+    }
+}
+
+fn run_pipe(command: &str, input: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let output = run_with_input(shell_command(command), input)?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(match stderr.trim() {
+            "" => format!("exited with {}", output.status),
+            stderr => format!("exited with {}: {}", output.status, stderr),
+        }
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The "Thinking..." indicator. On a terminal a background task animates a
+/// spinner next to it and swaps in the configured slow messages as time passes.
+struct ThinkingIndicator {
+    /// Set when the indicator is cleared. The task checks it under the lock
+    /// before drawing, so it can never draw over the response.
+    stopped: Arc<Mutex<bool>>,
+    width: usize,
+    /// The spinner task, ended when the indicator is cleared.
+    task: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl ThinkingIndicator {
+    /// Shows the indicator, unless it is turned off in the config.
+    fn start(settings: &Settings, quiet: bool) -> Option<ThinkingIndicator> {
+        let t = theme();
+        let message = settings.thinking_message.clone().filter(|_| !quiet)?;
+        let slow_messages = settings.slow_messages.clone();
+        let width = slow_messages
+            .iter()
+            .map(|slow| slow.message.as_str())
+            .chain([message.as_str()])
+            .map(|message| message.width() + 2)
+            .max()
+            .unwrap_or(0);
+
+        if !io::stdout().is_terminal() {
+            print!("{}", message);
+            io::stdout().flush().unwrap();
+            return Some(ThinkingIndicator {
+                stopped: Arc::new(Mutex::new(true)),
+                width,
+                task: None,
+            });
+        }
+
+        let stopped = Arc::new(Mutex::new(false));
+        let started = Instant::now();
+        let task = tokio::spawn({
+            let stopped = Arc::clone(&stopped);
+            async move {
+                for frame in glyphs().spinner.iter().cycle() {
+                    let elapsed = started.elapsed();
+                    let text = slow_messages
+                        .iter()
+                        .rev()
+                        .find(|slow| elapsed >= Duration::from_secs(slow.after))
+                        .map_or(message.as_str(), |slow| slow.message.as_str());
+                    {
+                        let stopped = stopped.lock().unwrap();
+                        if *stopped {
+                            break;
+                        }
+                        print!("\r{}{} {}{}\x1b[K", t.yellow, frame, text, t.reset);
+                        io::stdout().flush().unwrap();
+                    }
+                    tokio::time::sleep(SPINNER_INTERVAL).await;
+                }
+            }
+        });
+        Some(ThinkingIndicator {
+            stopped,
+            width,
+            task: Some(task),
+        })
+    }
+
+    /// Stops the animation and clears the indicator. The task only yields
+    /// between frames, so aborting it can't cut a frame short.
+    fn clear(self) {
+        *self.stopped.lock().unwrap() = true;
+        if let Some(task) = self.task {
+            task.abort();
+        }
+        print!("\r{}\r", " ".repeat(self.width));
+        io::stdout().flush().unwrap();
+    }
+}
+
+/// Prints the model's thoughts, or a streamed piece of them, dimmed and as
+/// written, to set them apart from the rendered answer.
+fn print_thoughts(text: &str) {
+    let t = theme();
+    print!("{}{}{}", t.dim, text, t.reset);
+    io::stdout().flush().unwrap();
+}
+
+/// Leaves a blank line between the thoughts and the answer.
+fn end_thoughts(ended_line: bool) {
+    if !ended_line {
+        println!();
+    }
+    println!();
+}
+
+/// Clears the "Thinking..." indicator, if there is one.
+fn clear_thinking(thinking: Option<ThinkingIndicator>) {
+    if let Some(thinking) = thinking {
+        thinking.clear();
+    }
+}
+
+/// Clears the "Thinking..." indicator and prints the response header.
+fn begin_response(thinking: Option<ThinkingIndicator>, quiet: bool) {
+    let t = theme();
+    clear_thinking(thinking);
+    if !quiet {
+        println!("{}Gemini:{}", t.bold, t.reset);
+    }
+}
+
+/// How often a request has been sent again, for `ask` and the one-shot
+/// `--html` and `--extract` requests.
+#[derive(Default)]
+struct Retries {
+    empty: bool,
+    network: u32,
+}
+
+impl Retries {
+    /// Whether a request that failed with `e` should be sent again: once for
+    /// an unexplained empty response, and up to `retries` times for network
+    /// errors and rate limits, after waiting out the delay. Once part of the
+    /// response has been shown (`started`), sending again would repeat it.
+    async fn wait(
+        &mut self,
+        e: &(dyn std::error::Error + 'static),
+        started: bool,
+        settings: &Settings,
+        thinking: &mut Option<ThinkingIndicator>,
+        quiet: bool,
+    ) -> bool {
+        let t = theme();
+        if settings.retry_empty && !self.empty && is_unexplained_empty(e) {
+            self.empty = true;
+            if settings.verbose {
+                // Clear the spinner's line first so the message isn't drawn over it.
+                clear_thinking(thinking.take());
+                eprintln!("{}The response was empty, retrying once.{}", t.dim, t.reset);
+                *thinking = ThinkingIndicator::start(settings, quiet);
+            }
+            return true;
+        }
+        if started || self.network >= settings.retries {
+            return false;
+        }
+        if is_transient_network_error(e) {
+            let delay = network_retry_delay(self.network, settings.retry_jitter);
+            self.network += 1;
+            if settings.verbose {
+                clear_thinking(thinking.take());
+                eprintln!(
+                    "{}{}; retrying in {:.1}s ({} of {}).{}",
+                    t.dim,
+                    e,
+                    delay.as_secs_f64(),
+                    self.network,
+                    settings.retries,
+                    t.reset
+                );
+                *thinking = ThinkingIndicator::start(settings, quiet);
+            }
+            tokio::time::sleep(delay).await;
+            return true;
+        }
+        // Shares the retry budget with network failures.
+        let Some(delay) = rate_limit_wait(e, self.network, settings.retry_jitter) else {
+            return false;
+        };
+        self.network += 1;
+        // Shown even without verbose, since the API may ask for a long wait.
+        clear_thinking(thinking.take());
+        eprintln!(
+            "{}Rate limited; retrying in {:.1}s ({} of {}).{}",
+            t.dim,
+            delay.as_secs_f64(),
+            self.network,
+            settings.retries,
+            t.reset
+        );
+        *thinking = ThinkingIndicator::start(settings, quiet);
+        tokio::time::sleep(delay).await;
+        true
+    }
+}
+
+/// Sends the request to Gemini and renders the reply, returning the reply
+/// text on success. Errors are reported here and passed back so callers can
+/// react to specific ones.
+async fn ask(
+    client: &Client,
+    api_key: &str,
+    request: &GeminiRequest<'_>,
+    settings: &Settings,
+    quiet: bool,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let t = theme();
+    if settings.echo_prompt {
+        // Secret prompts stay hidden even here.
+        match request.contents.last() {
+            Some(turn) if turn.role == Role::User && !turn.redacted => {
+                println!("{}{}{}", t.dim, turn.text().trim_end(), t.reset);
+            }
+            _ => {}
+        }
+    }
+    let mut thinking = ThinkingIndicator::start(settings, quiet);
+
+    let mut started = false;
+    let mut retries = Retries::default();
+    let result = loop {
+        // A pipe command needs the whole response, so don't stream into it,
+        // the candidate to show can only be picked once all have arrived, and
+        // low-confidence tokens are marked on the whole text.
+        let several_candidates = settings.generation_config().candidate_count > Some(1);
+        let result = if settings.stream
+            && settings.pipe.is_none()
+            && !several_candidates
+            && !settings.logprobs
+        {
+            let mut renderer = StreamRenderer::new(settings);
+            // Set while thoughts are on screen with no answer below them yet,
+            // to whether they ended their line.
+            let mut thoughts_shown: Option<bool> = None;
+            let mut stop_key = StopKey::watch();
+            let stop = async {
+                match &mut stop_key {
+                    Some(stop_key) => stop_key.pressed().await,
+                    None => std::future::pending().await,
+                }
+            };
+            let result = stream_from_gemini(
+                client,
+                api_key,
+                &settings.model,
+                request,
+                |part| {
+                    if part.thought && !settings.show_thoughts {
+                        return;
+                    }
+                    if !started {
+                        begin_response(thinking.take(), quiet);
+                        started = true;
+                    }
+                    if part.thought {
+                        print_thoughts(&part.text);
+                        thoughts_shown = Some(part.text.ends_with('\n'));
+                        return;
+                    }
+                    if let Some(ended_line) = thoughts_shown.take() {
+                        end_thoughts(ended_line);
+                    }
+                    renderer.push(&part.text, settings);
+                },
+                stop,
+            )
+            .await;
+            drop(stop_key);
+            let stopped = matches!(&result, Ok(streamed) if streamed.stopped);
+            renderer.finish(settings, stopped);
+            result.map(|streamed| {
+                if streamed.stopped {
+                    println!("{}[stopped]{}", t.dim, t.reset);
+                }
+                streamed.reply
+            })
+        } else {
+            send_to_gemini(
+                client,
+                api_key,
+                &settings.model,
+                request,
+                settings.select_candidate,
+            )
+            .await
+        };
+        let e = match result {
+            Err(e) => e,
+            result => break result,
+        };
+        if !retries
+            .wait(&*e, started, settings, &mut thinking, quiet)
+            .await
+        {
+            break Err(e);
+        }
+    };
+
+    match result {
+        Ok(reply) => {
+            if !started {
+                begin_response(thinking, quiet);
+                if settings.show_thoughts && !reply.thoughts.is_empty() {
+                    print_thoughts(reply.thoughts.trim_end());
+                    end_thoughts(false);
+                }
+                render_reply(&reply, settings);
+            }
+            reply.report(settings);
+            Ok(reply.text)
+        }
+        Err(e) => {
+            clear_thinking(thinking);
+            eprintln!("{}Error:{} {}", t.red, t.reset, e);
+            Err(e)
+        }
+    }
+}
+
+/// A saved conversation, stored as JSON in the sessions directory.
+#[derive(Serialize, Deserialize)]
+struct SessionFile {
+    contents: Vec<SavedTurn>,
+}
+
+/// A turn in a session file: the content as sent to the API, with its
+/// `TurnInfo` alongside. Sessions saved before turns had info load without it.
+#[derive(Serialize, Deserialize)]
+struct SavedTurn {
+    #[serde(flatten)]
+    content: Content,
+    #[serde(flatten)]
+    info: TurnInfo,
+}
+
+/// A saved conversation as listed by the `sessions` command.
+struct SavedSession {
+    path: PathBuf,
+    modified: SystemTime,
+    preview: String,
+}
+
+/// Returns the directory for the CLI's own files, `$XDG_CONFIG_HOME/gemini_cli`
+/// or `~/.config/gemini_cli`.
+fn config_dir() -> PathBuf {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_else(|| PathBuf::from("."));
+    base.join("gemini_cli")
+}
+
+fn sessions_dir() -> PathBuf {
+    config_dir().join("sessions")
+}
+
+/// The prompts typed in earlier REPL sessions, for the up arrow and Ctrl-R.
+fn history_path() -> PathBuf {
+    config_dir().join("history.txt")
+}
+
+/// Checks that a session name stays inside the sessions directory once joined onto it.
+fn validate_session_name(name: &str) -> Result<(), String> {
+    if name.contains(['/', '\\']) || name.contains("..") || Path::new(name).is_absolute() {
+        return Err(format!(
+            "Session names can't contain '/', '\\' or '..', got '{}'",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Saves the conversation under `name`, or a timestamp if no name is given.
+fn save_session(
+    conversation: &Conversation,
+    name: Option<&str>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if let Some(name) = name {
+        validate_session_name(name)?;
+    }
+    let dir = sessions_dir();
+    fs::create_dir_all(&dir)?;
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => Local::now().format("%Y%m%d-%H%M%S").to_string(),
+    };
+    let path = dir.join(format!("{}.json", name));
+    let session = SessionFile {
+        contents: conversation
+            .to_contents()
+            .into_iter()
+            .map(|content| SavedTurn {
+                info: content.info.clone(),
+                content,
+            })
+            .collect(),
+    };
+    fs::write(&path, serde_json::to_string_pretty(&session)?)?;
+    Ok(path)
+}
+
+fn load_session(path: &Path) -> Result<Conversation, Box<dyn std::error::Error>> {
+    let session: SessionFile = serde_json::from_str(&fs::read_to_string(path)?)?;
+    let contents = session.contents.into_iter().map(|turn| Content {
+        info: turn.info,
+        ..turn.content
+    });
+    Ok(Conversation::from_contents(contents.collect()))
+}
+
+/// Finds a session for `replay` by path, or by name in the sessions directory.
+fn find_session(name: &str) -> PathBuf {
+    let path = PathBuf::from(name);
+    if path.exists() {
+        return path;
+    }
+    sessions_dir().join(format!("{}.json", name))
+}
+
+/// Prints a saved session turn by turn as if it were happening, pausing
+/// `delay` before each turn.
+async fn replay_session(
+    path: &Path,
+    delay: Duration,
+    settings: &Settings,
+    quiet: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let t = theme();
+    let conversation = load_session(path)?;
+    if let Some(system) = &conversation.system_instruction {
+        println!("{}System: {}{}", t.dim, system, t.reset);
+    }
+    for turn in &conversation.turns {
+        tokio::time::sleep(delay).await;
+        match turn.role {
+            Role::User => println!("{}> {}{}", t.magenta, t.reset, turn.text()),
+            Role::Model => {
+                begin_response(None, quiet);
+                render_response(&turn.text(), settings);
+                if let (Some(model), Some(latency)) = (&turn.info.model, turn.info.latency_ms) {
+                    println!(
+                        "{}{} in {:.1}s{}",
+                        t.dim,
+                        model,
+                        latency as f64 / 1000.0,
+                        t.reset
+                    );
+                }
+            }
+            Role::System => {}
+        }
+    }
+    Ok(())
+}
+
+/// Lists saved sessions, most recently modified first. A missing sessions
+/// directory simply means there are no sessions yet.
+fn list_sessions() -> io::Result<Vec<SavedSession>> {
+    let entries = match fs::read_dir(sessions_dir()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut sessions = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        let modified = fs::metadata(&path)?.modified()?;
+        let preview = match load_session(&path) {
+            Ok(conversation) => conversation
+                .turns
+                .iter()
+                .find(|content| content.role == Role::User)
+                .map(|content| content.text().replace('\n', " "))
+                .unwrap_or_default(),
+            Err(_) => "(unreadable)".to_string(),
+        };
+        sessions.push(SavedSession {
+            path,
+            modified,
+            preview,
+        });
+    }
+    sessions.sort_by_key(|session| std::cmp::Reverse(session.modified));
+    Ok(sessions)
+}
+
+fn print_sessions() {
+    let t = theme();
+    let sessions = match list_sessions() {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            eprintln!("{}Error:{} {}", t.red, t.reset, e);
+            return;
+        }
+    };
+    if sessions.is_empty() {
+        println!(
+            "{}No saved sessions in {}{}",
+            t.dim,
+            sessions_dir().display(),
+            t.reset
+        );
+        return;
+    }
+
+    for (i, session) in sessions.iter().enumerate() {
+        let modified: DateTime<Local> = session.modified.into();
+        let name = session
+            .path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy())
+            .unwrap_or_default();
+        let preview = truncate_display(&session.preview, PREVIEW_WIDTH);
+        println!(
+            "{}{:3}.{} {}{}{} {}{}{}  {}",
+            t.magenta,
+            i + 1,
+            t.reset,
+            t.dim,
+            modified.format("%Y-%m-%d %H:%M"),
+            t.reset,
+            t.keyword,
+            name,
+            t.reset,
+            preview
+        );
+    }
+}
+
+/// Prints a word-level diff between two responses, with removed words in red
+/// and added words in green.
+fn print_word_diff(old: &str, new: &str) {
+    let t = theme();
+    println!("{}Changes from the previous response:{}", t.bold, t.reset);
+    let diff = TextDiff::from_words(old, new);
+    for change in diff.iter_all_changes() {
+        match change.tag() {
+            ChangeTag::Equal => print!("{}", change.value()),
+            ChangeTag::Delete => print!("{}{}{}", t.red, change.value(), t.reset),
+            ChangeTag::Insert => print!("{}{}{}", t.green, change.value(), t.reset),
+        }
+    }
+    println!();
+}
+
+/// Appends the text on the system clipboard to `question`, or returns it
+/// alone when there is no question.
+fn with_clipboard(question: &str) -> Result<String, String> {
+    let pasted = arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.get_text())
+        .map_err(|e| match e {
+            arboard::Error::ContentNotAvailable => "The clipboard is empty".to_string(),
+            e => format!("Could not read the clipboard: {}", e),
+        })?;
+    let pasted = pasted.trim_end();
+    if pasted.trim().is_empty() {
+        return Err("The clipboard is empty".to_string());
+    }
+    Ok(if question.is_empty() {
+        pasted.to_string()
+    } else {
+        format!("{}\n\n{}", question, pasted)
+    })
+}
+
+/// Replaces each `@file:<path>` in `input` with the file's contents, as fenced
+/// blocks placed before the rest of the prompt. Paths may be glob patterns
+/// such as `src/*.rs`. Files that would take the total past `MAX_FILE_BYTES`
+/// are skipped with a warning.
+fn expand_file_directives(input: &str) -> Result<String, String> {
+    let t = theme();
+    let mut files = String::new();
+    let mut prompt = String::new();
+    let mut total = 0;
+    for word in input.split_inclusive(char::is_whitespace) {
+        let Some(pattern) = word.trim_end().strip_prefix(FILE_DIRECTIVE) else {
+            prompt.push_str(word);
+            continue;
+        };
+        for path in matching_files(pattern)? {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+            if total + contents.len() > MAX_FILE_BYTES {
+                eprintln!(
+                    "{}Warning:{} Skipping {}: the files would exceed {} KB",
+                    t.yellow,
+                    t.reset,
+                    path.display(),
+                    MAX_FILE_BYTES / 1024
+                );
+                continue;
+            }
+            total += contents.len();
+            let language = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+            files.push_str(&format!(
+                "File: {}\n```{}\n{}\n```\n\n",
+                path.display(),
+                language,
+                contents.trim_end()
+            ));
+        }
+    }
+    Ok(files + prompt.trim())
+}
+
+/// Expands an `@file:` pattern to the files it names, sorted by path.
+fn matching_files(pattern: &str) -> Result<Vec<PathBuf>, String> {
+    if !pattern.contains(['*', '?', '[']) {
+        return Ok(vec![PathBuf::from(pattern)]);
+    }
+    let paths = glob::glob(pattern).map_err(|e| format!("Invalid pattern {}: {}", pattern, e))?;
+    let mut files: Vec<PathBuf> = paths
+        .filter_map(Result::ok)
+        .filter(|path| path.is_file())
+        .collect();
+    if files.is_empty() {
+        return Err(format!("No files match {}", pattern));
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Rough token count of the turns that would be sent with the next prompt,
+/// at about four characters per token. Only meant for the status line.
+fn approximate_tokens(conversation: &Conversation, settings: &Settings) -> usize {
+    let turns = recent_turns(&conversation.turns, settings.history_limit);
+    let system = conversation.system_instruction.as_deref().unwrap_or("");
+    let chars: usize = turns
+        .iter()
+        .map(|turn| turn.text().chars().count())
+        .sum::<usize>()
+        + system.chars().count();
+    chars.div_ceil(4)
+}
+
+/// One `bench` request: how long it took and how many tokens came back.
+struct BenchSample {
+    latency: Duration,
+    output_tokens: Option<u32>,
+}
+
+/// Sends `BENCH_PROMPT` `runs` times with at most `concurrency` requests in
+/// flight, then prints latency and output throughput statistics.
+async fn run_bench(
+    client: &Client,
+    api_key: &str,
+    settings: &Settings,
+    runs: usize,
+    concurrency: usize,
+) {
+    let t = theme();
+    let generation = settings.generation_config();
+    let mut tasks = tokio::task::JoinSet::new();
+    let mut started = 0;
+    let mut samples = Vec::new();
+    let mut errors = Vec::new();
+    while started < runs || !tasks.is_empty() {
+        while started < runs && tasks.len() < concurrency {
+            // A cheap handle on the same connection pool, not a new client.
+            let (client, api_key) = (client.clone(), api_key.to_string());
+            let (model, generation) = (settings.model.clone(), generation.clone());
+            tasks.spawn(async move {
+                let contents = [Content::new(Role::User, BENCH_PROMPT)];
+                let request = GeminiRequest {
+                    contents: &contents,
+                    system_instruction: None,
+                    generation_config: (!generation.is_empty()).then_some(generation),
+                    cached_content: None,
+                };
+                let start = Instant::now();
+                let reply = send_to_gemini(
+                    &client,
+                    &api_key,
+                    &model,
+                    &request,
+                    CandidateSelection::default(),
+                )
+                .await;
+                reply
+                    .map(|reply| BenchSample {
+                        latency: start.elapsed(),
+                        output_tokens: reply.usage.map(|usage| usage.candidates_token_count),
+                    })
+                    .map_err(|e| e.to_string())
+            });
+            started += 1;
+        }
+        match tasks.join_next().await {
+            Some(Ok(Ok(sample))) => samples.push(sample),
+            Some(Ok(Err(e))) => errors.push(e),
+            Some(Err(e)) => errors.push(e.to_string()),
+            None => {}
+        }
+        print!(
+            "\r{}{}/{} done{}",
+            t.dim,
+            samples.len() + errors.len(),
+            runs,
+            t.reset
+        );
+        io::stdout().flush().unwrap();
+    }
+    println!();
+
+    println!(
+        "{}{}{}: {} of {} requests succeeded, {} at a time",
+        t.bold,
+        settings.model,
+        t.reset,
+        samples.len(),
+        runs,
+        concurrency
+    );
+    if let Some(e) = errors.first() {
+        eprintln!("{}Error:{} {}", t.red, t.reset, e);
+    }
+    let mut latencies: Vec<f64> = samples
+        .iter()
+        .map(|sample| sample.latency.as_secs_f64() * 1000.0)
+        .collect();
+    let mut throughputs: Vec<f64> = samples
+        .iter()
+        .filter_map(|sample| Some(sample.output_tokens? as f64 / sample.latency.as_secs_f64()))
+        .collect();
+    if latencies.is_empty() {
+        return;
+    }
+    println!(
+        "{}{:<12}{:>9}{:>9}{:>9}{:>9}{:>9}{}",
+        t.dim, "", "min", "p50", "p95", "max", "mean", t.reset
+    );
+    let rows = [
+        ("latency (ms)", &mut latencies),
+        ("tokens/s", &mut throughputs),
+    ];
+    for (label, values) in rows {
+        if values.is_empty() {
+            continue;
+        }
+        values.sort_by(f64::total_cmp);
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        println!(
+            "{:<12}{:>9.1}{:>9.1}{:>9.1}{:>9.1}{:>9.1}",
+            label,
+            values[0],
+            percentile(values, 50.0),
+            percentile(values, 95.0),
+            values[values.len() - 1],
+            mean
+        );
+    }
+}
+
+/// The nearest-rank `p`th percentile of `sorted`, which must not be empty.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn print_statusline(conversation: &Conversation, settings: &Settings) {
+    let t = theme();
+    let separator = glyphs().separator;
+    println!(
+        "{}{} {} temperature {} {} ~{} tokens{}",
+        t.dim,
+        settings.model_label(),
+        separator,
+        optional_label(settings.generation_config().temperature),
+        separator,
+        approximate_tokens(conversation, settings),
+        t.reset
+    );
+}
+
+/// Replaces all but the last `keep` turns with a summary written by the model.
+/// Returns how many turns were summarized.
+async fn summarize_history(
+    client: &Client,
+    api_key: &str,
+    model: &str,
+    conversation: &mut Conversation,
+    keep: usize,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let recent = recent_turns(&conversation.turns, Some(keep)).len();
+    let older = conversation.turns.len() - recent;
+    if older == 0 {
+        return Ok(0);
+    }
+    let mut contents = conversation.turns[..older].to_vec();
+    contents.push(Content::new(Role::User, SUMMARIZE_PROMPT));
+    let request = GeminiRequest {
+        contents: &contents,
+        system_instruction: None,
+        generation_config: None,
+        cached_content: None,
+    };
+    let summary = send_to_gemini(
+        client,
+        api_key,
+        model,
+        &request,
+        CandidateSelection::default(),
+    )
+    .await?
+    .text;
+    // A summary of secret prompts could repeat them, so it stays out of saves too.
+    let summary = Content {
+        redacted: contents.iter().any(|turn| turn.redacted),
+        ..Content::new(Role::User, &format!("{}{}", SUMMARY_PREFIX, summary.trim()))
+    };
+    let next_role = conversation.turns.get(older).map(|turn| turn.role);
+    conversation
+        .turns
+        .splice(..older, summary_turns(summary, next_role));
+    conversation.unsaved = true;
+    Ok(older)
+}
+
+/// The turns that replace the summarized ones: the summary as a user turn,
+/// followed by a short model reply unless the next kept turn is one already,
+/// so two user turns never meet.
+fn summary_turns(summary: Content, next_role: Option<Role>) -> Vec<Content> {
+    if next_role == Some(Role::Model) {
+        return vec![summary];
+    }
+    vec![summary, Content::new(Role::Model, SUMMARY_ACKNOWLEDGEMENT)]
+}
+
+/// Lists the conversation's turns with a one-line preview of each.
+fn print_history(conversation: &Conversation) {
+    let t = theme();
+    if conversation.turns.is_empty() {
+        println!("{}The conversation is empty.{}", t.dim, t.reset);
+        return;
+    }
+    for (i, turn) in conversation.turns.iter().enumerate() {
+        let text = if turn.redacted {
+            REDACTED_TEXT.to_string()
+        } else {
+            turn.text()
+        };
+        let (label, color, text) = match (turn.role, text.strip_prefix(SUMMARY_PREFIX)) {
+            (_, Some(summary)) => ("Summary", t.yellow, summary),
+            (Role::Model, None) => ("Gemini", t.blue, text.as_str()),
+            _ => ("You", t.green, text.as_str()),
+        };
+        let text = text.replace('\n', " ");
+        let preview = truncate_display(&text, PREVIEW_WIDTH);
+        println!(
+            "{}{:3}.{} {}{:<8}{} {}",
+            t.magenta,
+            i + 1,
+            t.reset,
+            color,
+            label,
+            t.reset,
+            preview
+        );
+    }
+}
+
+/// Drops the conversation's cached context if `error` shows it has expired.
+fn forget_expired_cache(
+    conversation: &mut Conversation,
+    error: &(dyn std::error::Error + 'static),
+) {
+    let t = theme();
+    if conversation.cache.is_some() && is_cache_error(error) {
+        conversation.cache = None;
+        println!(
+            "{}The cached context has expired; run 'cache-context @file:<path>' to cache it again.{}",
+            t.yellow, t.reset
+        );
+    }
+}
+
+/// REPL commands, for tab completion.
+const COMMANDS: &[&str] = &[
+    "help",
+    "clear",
+    "clear!",
+    "set",
+    "model",
+    "system",
+    "retry",
+    "amend",
+    "compare",
+    "attach",
+    "secret",
+    "paste",
+    "cache-context",
+    "history",
+    "history-clear",
+    "run",
+    "links",
+    "open",
+    "write",
+    "rerender",
+    "fork",
+    "back",
+    "unfork",
+    "session",
+    "summarize",
+    "reset",
+    "save",
+    "sessions",
+    "resume",
+    "replay",
+    "quit",
+    "exit",
+];
+
+/// Commands that work but aren't listed in `help` or completed.
+const HIDDEN_COMMANDS: &[&str] = &["bench"];
+
+/// Tab completion for the REPL: command names, setting keys and values,
+/// model names, and file paths for `attach`.
+struct ReplHelper {
+    models: Vec<String>,
+    /// Names of the custom commands from the config file.
+    custom_commands: Vec<String>,
+    files: FilenameCompleter,
+    /// Mirrors `Settings::empty_input`, which decides when Enter submits.
+    empty_input: EmptyInput,
+}
+
+impl ReplHelper {
+    fn new(models: Vec<String>, custom_commands: Vec<String>) -> Self {
+        ReplHelper {
+            models,
+            custom_commands,
+            files: FilenameCompleter::new(),
+            empty_input: EmptyInput::Ignore,
+        }
+    }
+
+    /// Everything that may follow `words`, the complete words before the cursor.
+    fn options(&self, words: &[&str]) -> Vec<String> {
+        let strings = |options: &[&str]| options.iter().map(|s| s.to_string()).collect();
+        match words {
+            [] => COMMANDS
+                .iter()
+                .map(|command| command.to_string())
+                .chain(self.custom_commands.iter().cloned())
+                .collect(),
+            ["set"] => Settings::default()
+                .entries()
+                .into_iter()
+                .map(|(key, _)| key.to_string())
+                .filter(|key| key != "model")
+                .collect(),
+            ["set", "render"] => strings(&["markdown", "source", "raw"]),
+            ["set", "empty-input"] => strings(&["ignore", "repeat", "newline"]),
+            ["set", "select-candidate"] => strings(&["best", "1", "2"]),
+            ["set", "wrapper"] => strings(&["none"]),
+            ["set", "theme"] => {
+                let printer = PrettyPrinter::new();
+                let themes = printer.themes().map(str::to_string);
+                ["default".to_string()].into_iter().chain(themes).collect()
+            }
+            ["set", key] if SWITCH_SETTINGS.contains(key) => strings(&["on", "off"]),
+            ["model"] | ["compare"] => self.models.clone(),
+            ["system"] | ["cache-context"] => strings(&["off"]),
+            ["retry"] | ["compare", _] => strings(&["--diff"]),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Settings that take `on` or `off`.
+const SWITCH_SETTINGS: &[&str] = &[
+    "inline-bg",
+    "confirm-cost",
+    "stream",
+    "strike-dim",
+    "math",
+    "statusline",
+    "usage",
+    "logprobs",
+    "line-numbers",
+    "retry-empty",
+    "autojson",
+    "autorender",
+    "break-long",
+    "mermaid-link",
+    "show-thoughts",
+    "bare-code",
+    "trim",
+    "verbose",
+    "echo-prompt",
+];
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        if line.starts_with("attach ") || line.starts_with("replay ") {
+            return self.files.complete(line, pos, ctx);
+        }
+        let line = &line[..pos];
+        let start = line.rfind(' ').map_or(0, |space| space + 1);
+        let words: Vec<&str> = line[..start].split_whitespace().collect();
+        let word = &line[start..];
+        let candidates = self
+            .options(&words)
+            .into_iter()
+            .filter(|option| option.starts_with(word))
+            .map(|option| Pair {
+                display: option.clone(),
+                replacement: option,
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if self.empty_input == EmptyInput::Newline && !ends_multiline_prompt(ctx.input()) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+impl rustyline::Helper for ReplHelper {}
+
+/// Splits a `--diff` flag out of command arguments.
+fn take_diff_flag(args: &str) -> (Vec<&str>, bool) {
+    let mut diff = false;
+    let rest = args
+        .split_whitespace()
+        .filter(|arg| {
+            let is_flag = *arg == "--diff";
+            diff |= is_flag;
+            !is_flag
+        })
+        .collect();
+    (rest, diff)
+}
+
+/// Asks a yes/no question on stdin, defaulting to no.
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    io::stdout().flush().unwrap();
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// Asks whether a large prompt should really be sent, showing its token count
+/// and estimated cost. Prompts below the threshold are always sent.
+async fn confirm_cost(
+    client: &Client,
+    api_key: &str,
+    contents: &[Content],
+    settings: &Settings,
+) -> bool {
+    let t = theme();
+    let tokens = match count_tokens(client, api_key, &settings.model, contents).await {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            eprintln!("{}Could not count tokens:{} {}", t.yellow, t.reset, e);
+            return true;
+        }
+    };
+    if tokens < settings.cost_threshold {
+        return true;
+    }
+
+    match estimate_cost(&settings.model, tokens) {
+        Some(cost) => println!(
+            "{}This prompt is about {} tokens (~${:.4} input cost).{}",
+            t.yellow, tokens, cost, t.reset
+        ),
+        None => println!(
+            "{}This prompt is about {} tokens.{}",
+            t.yellow, tokens, t.reset
+        ),
+    }
+    confirm("Send it?")
+}
+
+fn print_missing_key_help() {
+    let t = theme();
+    eprintln!("{}Error:{} No Gemini API key found.", t.red, t.reset);
+    eprintln!();
+    eprintln!("Get a free key from Google AI Studio at https://aistudio.google.com/app/apikey");
+    eprintln!("and set it in the {} environment variable:", API_KEY_ENV);
+    eprintln!();
+    eprintln!("    export {}=\"your-api-key\"", API_KEY_ENV);
+    eprintln!();
+    eprintln!(
+        "or add api_key = \"your-api-key\" to {}.",
+        config_path().display()
+    );
+}
+
+/// First-run setup for when no API key is configured: explains where to get
+/// one, reads it without echo, optionally checks it with a test request and
+/// offers to save it to the config file. Returns `None` if the user gives up.
+async fn setup_api_key(client: &Client, config_file: &Path) -> Option<String> {
+    let t = theme();
+    println!("{}Let's set up a Gemini API key.{}", t.bold, t.reset);
+    println!();
+    println!("Get a free key from Google AI Studio at https://aistudio.google.com/app/apikey");
+    println!("(sign in, choose \"Create API key\" and copy it), then paste it below.");
+    println!("{}Press Enter without a key to quit.{}", t.dim, t.reset);
+    println!();
+    let key = loop {
+        let key = match rpassword::prompt_password("API key (hidden): ") {
+            Ok(key) => key.trim().to_string(),
+            Err(e) => {
+                eprintln!("{}Error:{} {}", t.red, t.reset, e);
+                return None;
+            }
+        };
+        if key.is_empty() {
+            return None;
+        }
+        if !confirm("Check the key with a test request?") {
+            break key;
+        }
+        match list_models(client, &key).await {
+            Ok(_) => {
+                println!("{}The key works.{}", t.green, t.reset);
+                break key;
+            }
+            Err(e) => eprintln!(
+                "{}Error:{} The key didn't work: {}. Try again, or press Enter to quit.",
+                t.red, t.reset, e
+            ),
+        }
+    };
+    if confirm(&format!("Save the key to {}?", config_file.display())) {
+        match save_api_key(config_file, &key) {
+            Ok(()) => println!("{}Saved.{}", t.dim, t.reset),
+            Err(e) => eprintln!(
+                "{}Warning:{} Could not save {}: {}",
+                t.yellow,
+                t.reset,
+                config_file.display(),
+                e
+            ),
+        }
+    } else {
+        println!(
+            "{}The key is used for this session only; set {} to keep it.{}",
+            t.dim, API_KEY_ENV, t.reset
+        );
+    }
+    println!();
+    Some(key)
+}
+
+/// Writes `api_key` into the config file, keeping the rest of it. The file is
+/// readable by its owner only, since it now holds a secret.
+fn save_api_key(path: &Path, key: &str) -> io::Result<()> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // Created owner-only, so the key is never readable by others, even briefly.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // `mode` only applies to a new file; tighten an existing one before
+        // the key goes into it.
+        if path.exists() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    options
+        .open(path)?
+        .write_all(with_api_key(&text, key).as_bytes())
+}
+
+/// Sets the top-level `api_key` in config file text: replaces an existing
+/// line, or adds one at the top, before any table that would swallow it.
+fn with_api_key(text: &str, key: &str) -> String {
+    let line = format!("api_key = {}", toml::Value::String(key.to_string()));
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+    let top_level = lines
+        .iter()
+        .position(|l| l.trim_start().starts_with('['))
+        .unwrap_or(lines.len());
+    let existing = lines[..top_level].iter().position(|l| {
+        l.split_once('=')
+            .is_some_and(|(name, _)| name.trim() == "api_key")
+    });
+    match existing {
+        Some(i) => lines[i] = line,
+        None => lines.insert(0, line),
+    }
+    let mut text = lines.join("\n");
+    text.push('\n');
+    text
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    // Keep the indicator and header out of an HTML page or extract written to stdout.
+    let quiet = cli.quiet || cli.ci || (cli.html && cli.output.is_none()) || cli.extract.is_some();
+    if cli.no_color || cli.ci || env::var_os("NO_COLOR").is_some() || terminal_lacks_color() {
+        COLOR_ENABLED.store(false, Ordering::Relaxed);
+    }
+    if cli.ascii || locale_is_limited() {
+        ASCII_ENABLED.store(true, Ordering::Relaxed);
+    }
+    let t = theme();
+    let config_file = cli.config.clone().unwrap_or_else(config_path);
+    let config = match load_config(&config_file, cli.config.is_some()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}Error:{} {}", t.red, t.reset, e);
+            return Ok(ExitCode::from(2));
+        }
+    };
+
+    if let Some(base_url) = &cli.base_url {
+        match resolve_base_url(base_url, cli.allow_http) {
+            Ok(base_url) => {
+                API_BASE_URL.get_or_init(|| base_url);
+            }
+            Err(e) => {
+                eprintln!("{}Error:{} {}", t.red, t.reset, e);
+                return Ok(ExitCode::from(2));
+            }
+        }
+    }
+    if cli.insecure {
+        eprintln!(
+            "{}{}Warning:{} --insecure is set, so TLS certificates are NOT verified. Anyone on the network path can read and change your requests, including your API key.",
+            t.bold, t.red, t.reset
+        );
+    }
+    let mut headers = HeaderMap::new();
+    let api_client = config.api_client.as_deref().unwrap_or(DEFAULT_API_CLIENT);
+    // A --header naming it wins over the config file.
+    if !api_client.is_empty()
+        && !cli
+            .headers
+            .iter()
+            .any(|(name, _)| name == API_CLIENT_HEADER)
+    {
+        match HeaderValue::from_str(api_client) {
+            Ok(value) => {
+                headers.insert(API_CLIENT_HEADER, value);
+            }
+            Err(_) => eprintln!(
+                "{}Warning:{} api_client '{}' isn't a valid header value; leaving out {}.",
+                t.yellow, t.reset, api_client, API_CLIENT_HEADER
+            ),
+        }
+    }
+    headers.extend(cli.headers.iter().cloned());
+    let mut client = Client::builder()
+        .default_headers(headers)
+        .danger_accept_invalid_certs(cli.insecure);
+    if cli.http1 {
+        client = client.http1_only();
+    }
+    if let Some(max_idle) = config.pool_max_idle_per_host {
+        client = client.pool_max_idle_per_host(max_idle);
+    }
+    // The one client for the whole run: every request goes through it, and
+    // clones share its connection pool.
+    let client = client.build()?;
+    let key_from_env = env::var(API_KEY_ENV).is_ok_and(|key| !key.trim().is_empty());
+    let configured_key = env::var(API_KEY_ENV)
+        .ok()
+        .or_else(|| config.api_key.clone())
+        .filter(|key| !key.trim().is_empty());
+    let interactive = !cli.ci && io::stdin().is_terminal() && io::stdout().is_terminal();
+    if cli.interactive_auth && !interactive {
+        eprintln!(
+            "{}Error:{} --interactive-auth needs a terminal.",
+            t.red, t.reset
+        );
+        return Ok(ExitCode::from(2));
+    }
+    let api_key = match configured_key {
+        Some(key) if !cli.interactive_auth => key,
+        _ if interactive => match setup_api_key(&client, &config_file).await {
+            Some(key) => key,
+            None => {
+                print_missing_key_help();
+                return Ok(ExitCode::from(2));
+            }
+        },
+        _ => {
+            print_missing_key_help();
+            return Ok(ExitCode::from(2));
+        }
+    };
+    // A key entered at the prompt was saved to the config file.
+    API_KEY_SOURCE.get_or_init(|| {
+        if key_from_env && !cli.interactive_auth {
+            API_KEY_ENV.to_string()
+        } else {
+            format!("api_key in {}", config_file.display())
+        }
+    });
+    // Check the configured theme once here rather than failing on every code block.
+    let theme = config.theme.filter(|name| match validate_theme(name) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!(
+                "{}Warning:{} {} Using the default theme.",
+                t.yellow, t.reset, e
+            );
+            false
+        }
+    });
+    let prompt_wrapper = match config
+        .prompt_wrapper
+        .as_deref()
+        .map(parse_prompt_wrapper)
+        .transpose()
+    {
+        Ok(wrapper) => wrapper,
+        Err(e) => {
+            eprintln!(
+                "{}Error:{} {} in {}",
+                t.red,
+                t.reset,
+                e,
+                config_file.display()
+            );
+            return Ok(ExitCode::from(2));
+        }
+    };
+    let (gen_config, unknown_fields) = cli.gen_config.clone().unwrap_or_default();
+    for field in unknown_fields {
+        eprintln!(
+            "{}Warning:{} Ignoring '{}' in --gen-config; known fields are {}.",
+            t.yellow,
+            t.reset,
+            field,
+            GENERATION_CONFIG_FIELDS.join(", ")
+        );
+    }
+    let aliases = model_aliases(config.aliases);
+    let model = match resolve_model(cli.model.clone(), config.model, &config_file, &aliases) {
+        Ok(model) => model,
+        Err(e) => {
+            eprintln!("{}Error:{} {}", t.red, t.reset, e);
+            return Ok(ExitCode::from(2));
+        }
+    };
+    let mut settings = Settings {
+        model,
+        aliases,
+        stream: cli.stream,
+        usage: cli.usage,
+        logprobs: cli.logprobs,
+        verbose: cli.verbose,
+        theme,
+        history_limit: cli.history_limit,
+        retries: cli.retries,
+        retry_jitter: config.retry_jitter,
+        pipe: cli.pipe.clone(),
+        prompt_wrapper,
+        render: if cli.no_markdown {
+            RenderMode::Raw
+        } else {
+            RenderMode::Markdown
+        },
+        slow_messages: match config.slow_messages {
+            Some(mut slow_messages) => {
+                slow_messages.sort_by_key(|slow| slow.after);
+                slow_messages
+            }
+            None => default_slow_messages(),
+        },
+        thinking_message: config.thinking.then(|| {
+            config
+                .thinking_message
+                .unwrap_or_else(|| DEFAULT_THINKING_MESSAGE.to_string())
+        }),
+        generation: GenerationConfig {
+            temperature: cli.temperature,
+            top_k: cli.top_k,
+            seed: cli.seed,
+            thinking_config: cli.thinking_budget.map(ThinkingConfig::budget),
+            candidate_count: cli.candidates,
+            presence_penalty: cli.presence_penalty,
+            frequency_penalty: cli.frequency_penalty,
+            response_logprobs: None,
+        }
+        .or(gen_config),
+        select_candidate: cli.select_candidate,
+        model_defaults: config
+            .models
+            .into_iter()
+            .map(|(model, defaults)| (model, defaults.into()))
+            .collect(),
+        ..Settings::default()
+    };
+
+    let system_instruction = starting_system_instruction(&cli, settings.verbose);
+
+    if let Some(path) = &cli.raw_request {
+        let body = match fs::read_to_string(path) {
+            Ok(body) => body,
+            Err(e) => {
+                eprintln!(
+                    "{}Error:{} Could not read {}: {}",
+                    t.red,
+                    t.reset,
+                    path.display(),
+                    e
+                );
+                return Ok(ExitCode::from(2));
+            }
+        };
+        let thinking = ThinkingIndicator::start(&settings, quiet);
+        return Ok(
+            match send_raw_request(&client, &api_key, &settings.model, body).await {
+                Ok(reply) => {
+                    begin_response(thinking, quiet);
+                    render_reply(&reply, &settings);
+                    reply.report(&settings);
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    clear_thinking(thinking);
+                    eprintln!("{}Error:{} {}", t.red, t.reset, e);
+                    ExitCode::FAILURE
+                }
+            },
+        );
+    }
+
+    if cli.ci
+        || cli.no_input_echo
+        || cli.from_clipboard
+        || cli.html
+        || cli.extract.is_some()
+        || (!cli.prompt.is_empty() && !cli.once)
+    {
+        let prompt = if cli.no_input_echo {
+            rpassword::prompt_password("Prompt (hidden): ")?
+        } else if cli.prompt.is_empty() && !io::stdin().is_terminal() {
+            let mut buffer = String::new();
+            io::stdin().read_to_string(&mut buffer)?;
+            buffer
+        } else {
+            cli.prompt.join(" ")
+        };
+        let prompt = if cli.from_clipboard {
+            match with_clipboard(prompt.trim()) {
+                Ok(prompt) => prompt,
+                Err(e) => {
+                    eprintln!("{}Error:{} {}", t.red, t.reset, e);
+                    return Ok(ExitCode::from(2));
+                }
+            }
+        } else {
+            prompt
+        };
+        let prompt = prompt.trim();
+        if prompt.is_empty() {
+            eprintln!(
+                "{}Error:{} No prompt given on the command line or stdin",
+                t.red, t.reset
+            );
+            return Ok(ExitCode::from(2));
+        }
+        let prompt = match parse_model_override(prompt) {
+            Some((model, rest)) => {
+                match resolve_model_name(model, &settings.aliases) {
+                    Ok(model) => settings.model = model,
+                    Err(e) => {
+                        eprintln!("{}Error:{} {}", t.red, t.reset, e);
+                        return Ok(ExitCode::from(2));
+                    }
+                }
+                rest
+            }
+            None => prompt,
+        };
+        let prompt = match expand_file_directives(prompt) {
+            Ok(prompt) => settings.wrap_prompt(prompt),
+            Err(e) => {
+                eprintln!("{}Error:{} {}", t.red, t.reset, e);
+                return Ok(ExitCode::from(2));
+            }
+        };
+        let mut attachments = Vec::new();
+        for path in &cli.attach {
+            match upload_file(&client, &api_key, path, quiet).await {
+                Ok(part) => attachments.push(part),
+                Err(e) => {
+                    eprintln!("{}Error:{} {}", t.red, t.reset, e);
+                    return Ok(ExitCode::FAILURE);
+                }
+            }
+        }
+        let conversation = Conversation {
+            system_instruction: system_instruction.clone(),
+            turns: vec![Content::with_attachments(attachments, &prompt)],
+            ..Conversation::default()
+        };
+        if cli.html || cli.extract.is_some() {
+            let request = conversation.request(&settings);
+            let mut thinking = ThinkingIndicator::start(&settings, quiet);
+            let mut retries = Retries::default();
+            let reply = loop {
+                let reply = send_to_gemini(
+                    &client,
+                    &api_key,
+                    &settings.model,
+                    &request,
+                    settings.select_candidate,
+                )
+                .await;
+                match reply {
+                    Err(e)
+                        if retries
+                            .wait(&*e, false, &settings, &mut thinking, quiet)
+                            .await => {}
+                    reply => break reply,
+                }
+            };
+            clear_thinking(thinking);
+            let written = reply.and_then(|reply| {
+                if let Some(pattern) = &cli.extract {
+                    let extracted = extract(pattern, &reply.text).ok_or_else(|| {
+                        format!("--extract '{}' matched nothing in the response", pattern)
+                    })?;
+                    println!("{}", extracted.strip_suffix('\n').unwrap_or(extracted));
+                    return Ok(());
+                }
+                let html = render_html(&reply.text);
+                match &cli.output {
+                    Some(path) => fs::write(path, html)?,
+                    None => print!("{}", html),
+                }
+                Ok(())
+            });
+            return Ok(match written {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!("{}Error:{} {}", t.red, t.reset, e);
+                    ExitCode::FAILURE
+                }
+            });
+        }
+        let reply = ask(
+            &client,
+            &api_key,
+            &conversation.request(&settings),
+            &settings,
+            quiet,
+        )
+        .await;
+        return Ok(if reply.is_ok() {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
+    }
+
+    // Without the check, complete model names from the ones this client knows about.
+    let models = if cli.no_check {
+        let mut models: Vec<String> = INPUT_PRICING
+            .iter()
+            .map(|(model, _)| model.to_string())
+            .chain([DEFAULT_MODEL.to_string()])
+            .chain(settings.model_defaults.keys().cloned())
+            .collect();
+        models.sort();
+        models.dedup();
+        models
+    } else {
+        match list_models(&client, &api_key).await {
+            Ok(models) => models,
+            Err(e) => {
+                eprintln!("{}Error:{} {}", t.red, t.reset, e);
+                eprintln!(
+                    "{}Check your API key and network connection, or start with --no-check to skip this check.{}",
+                    t.dim, t.reset
+                );
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+    };
+
+    let mut conversation = Conversation {
+        system_instruction,
+        ..Conversation::default()
+    };
+    // Files uploaded with `attach`, sent along with the next prompt.
+    let mut attachments = Vec::new();
+    for path in &cli.attach {
+        match upload_file(&client, &api_key, path, quiet).await {
+            Ok(part) => attachments.push(part),
+            Err(e) => eprintln!("{}Error:{} {}", t.red, t.reset, e),
+        }
+    }
+
+    let editor_config = rustyline::Config::builder()
+        .edit_mode(config.edit_mode.into())
+        .auto_add_history(true)
+        .max_history_size(config.history_size)?
+        .history_ignore_dups(config.history_dedup)?
+        .build();
+    let mut editor = Editor::with_config(editor_config)?;
+    let history_file = history_path();
+    match editor.load_history(&history_file) {
+        Ok(()) => {}
+        Err(ReadlineError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => eprintln!(
+            "{}Warning:{} Could not read the prompt history from {}: {}",
+            t.yellow,
+            t.reset,
+            history_file.display(),
+            e
+        ),
+    }
+    // Only the first failure to save it is worth a warning.
+    let mut history_saved = true;
+    // Emacs mode has both already; vi insert mode lacks Ctrl-L.
+    editor.bind_sequence(KeyEvent::ctrl('U'), Cmd::Kill(Movement::BeginningOfLine));
+    editor.bind_sequence(KeyEvent::ctrl('L'), Cmd::ClearScreen);
+    let custom_commands = custom_commands(config.commands);
+    // Aliases complete wherever a model name does.
+    let mut aliases: Vec<String> = settings.aliases.keys().cloned().collect();
+    aliases.sort();
+    editor.set_helper(Some(ReplHelper::new(
+        models.into_iter().chain(aliases).collect(),
+        custom_commands.keys().cloned().collect(),
+    )));
+    // Turns saved by `fork`, with whether they were unsaved, restored by `back`.
+    let mut forks: Vec<(Vec<Content>, bool)> = Vec::new();
+    // The active session, and the others started with `session new`. Names
+    // only show in the prompt once there is more than one.
+    let mut session_name = FIRST_SESSION_NAME.to_string();
+    let mut session_color = session_colors()[0];
+    let mut sessions: Vec<NamedSession> = Vec::new();
+    // The last prompt sent, for `set empty-input repeat`.
+    let mut last_prompt: Option<String> = None;
+    // With `--once`, the command-line prompt is handled as if typed first.
+    let mut initial_prompt = cli.once.then(|| cli.prompt.join(" "));
+
+    if !quiet {
+        println!();
+    }
+
+    loop {
+        if settings.statusline {
+            print_statusline(&conversation, &settings);
+        }
+        let prompt = match forks.len() {
+            0 => format!("{}> {}", t.magenta, t.reset),
+            depth => format!(
+                "{}fork {}{} {}> {}",
+                t.dim, depth, t.reset, t.magenta, t.reset
+            ),
+        };
+        let prompt = if sessions.is_empty() {
+            prompt
+        } else {
+            format!("{}{}{} {}", session_color, session_name, t.reset, prompt)
+        };
+        if let Some(helper) = editor.helper_mut() {
+            helper.empty_input = settings.empty_input;
+        }
+        let input = if let Some(line) = initial_prompt.take() {
+            println!("{}{}", prompt, line);
+            let _ = editor.add_history_entry(line.as_str());
+            line
+        } else {
+            match editor.readline(&prompt) {
+                Ok(line) => {
+                    // Saved line by line, so history survives a crash and is
+                    // shared with other REPLs running at the same time.
+                    let saved = fs::create_dir_all(config_dir())
+                        .map_err(ReadlineError::from)
+                        .and_then(|()| editor.append_history(&history_file));
+                    if let (Err(e), true) = (saved, history_saved) {
+                        eprintln!(
+                            "{}Warning:{} Could not save the prompt history to {}: {}",
+                            t.yellow,
+                            t.reset,
+                            history_file.display(),
+                            e
+                        );
+                        history_saved = false;
+                    }
+                    line
+                }
+                Err(ReadlineError::Interrupted) => continue, // Ctrl-C discards the line
+                Err(ReadlineError::Eof) => {
+                    // Ctrl-D leaves the cursor after the prompt.
+                    println!();
+                    break;
+                }
+                Err(_) => {
+                    eprintln!("{}Error reading input.{}", t.red, t.reset);
+                    break;
+                }
+            }
+        };
+
+        let input = match (input.trim(), settings.empty_input) {
+            ("", EmptyInput::Repeat) => match &last_prompt {
+                Some(prompt) => {
+                    println!("{}{}{}", t.dim, prompt, t.reset);
+                    prompt.clone()
+                }
+                None => continue,
+            },
+            (input, _) => input.to_string(),
+        };
+        let input = input.as_str();
+
+        match input {
+            "" => continue,
+            "quit" | "exit" => break,
+            "clear" | "clear!" => {
+                if input == "clear"
+                    && conversation.unsaved
+                    && !confirm("The conversation hasn't been saved. Clear the screen anyway?")
+                {
+                    continue;
+                }
+                print!("\x1b[2J\x1b[H");
+                io::stdout().flush().unwrap();
+                continue;
+            }
+            "help" => {
+                println!("{}Available Commands:{}", t.bold, t.reset);
+                println!(
+                    "  {}help{}      - Show this help message",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}clear{}     - Clear the terminal screen; asks first if the conversation is unsaved, 'clear!' doesn't",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}set{}       - Show settings, or change one with 'set <key> <value>'",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}model{}     - Show the active model, or switch with 'model <name>'",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}system{}    - Show, set ('system <text>') or clear ('system off') the system instruction",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}retry{}     - Ask the last question again; add --diff to see what changed",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}amend{}     - Replace your last question with 'amend <new text>' and ask again",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}compare{}   - Ask another model the last question with 'compare <model> [--diff]'",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}cache-context{} - Cache large context with 'cache-context @file:<path>', 'off' drops it",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}attach{}    - Upload a PDF or text file to send with the next prompt, 'attach <path>'",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}secret{}    - Type a prompt without echo; it isn't shown in history or saved",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}paste{}     - Send the clipboard, after a question if given: 'paste [question]'",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}history{}   - List the turns of the current conversation",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}history-clear{} - Delete the saved prompt history used by the up arrow and Ctrl-R",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}run{}       - Run a shell command with the last response (or its one code block) on stdin, e.g. 'run bash'; needs --allow-exec",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}links{}     - List the links in the last response",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}open{}      - Open a link from the last response in the browser, 'open [n]' (default: the last one)",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}write{}     - Save the last code block of the last response to a file, 'write [n] <path>' for the nth",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}rerender{}  - Show the last response again with the current settings",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}fork{}      - Try a tangent; 'back' (or 'unfork') returns to the conversation as it was",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}session{}   - List sessions, or keep several side by side with 'session new <name>' and 'session switch <name>'",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}summarize{} - Replace older turns with a summary, keeping the last few ('summarize <n>')",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}reset{}     - Forget the current conversation",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}save{}      - Save the conversation, optionally as 'save <name>'",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}sessions{}  - List saved conversations",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}resume{}    - Load a saved conversation with 'resume <n>'",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}replay{}    - Play back a saved conversation with 'replay <file> [seconds between turns]'",
+                    t.keyword, t.reset
+                );
+                println!("  {}quit/exit{} - Exit the REPL", t.keyword, t.reset);
+                println!("\n{}Keys:{}", t.bold, t.reset);
+                println!(
+                    "  {}Ctrl-U{}    - Clear the input before the cursor",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}Ctrl-L{}    - Clear the screen, keeping the conversation and your input",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}Ctrl-C{}    - Discard the input; while a response streams, stop it with q or Esc",
+                    t.keyword, t.reset
+                );
+                println!("  {}Ctrl-D{}    - Exit the REPL", t.keyword, t.reset);
+                if !custom_commands.is_empty() {
+                    println!(
+                        "\n{}Custom commands{} (from the config file):",
+                        t.bold, t.reset
+                    );
+                    let mut names: Vec<&String> = custom_commands.keys().collect();
+                    names.sort();
+                    for name in names {
+                        println!(
+                            "  {}{}{} - runs '{}'",
+                            t.keyword, name, t.reset, custom_commands[name].run
+                        );
+                    }
+                }
+                println!(
+                    "\n{}Generation settings{} (change with 'set <key> <value>', 'off' restores the default):",
+                    t.bold, t.reset
+                );
+                println!(
+                    "  {}temperature{} - 0.0 to 2.0, lower is more focused and deterministic",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}top-k{}       - Sample from the K most likely tokens; too low can make output repetitive",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}seed{}        - Fixed seed for reproducible answers; combine with temperature 0",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}thinking-budget{} - Tokens the model may spend thinking; 0 is off, -1 lets it decide",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}candidates{}  - Ask for 1 to 8 alternative answers; 'set select-candidate <n|best>' picks one",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}presence-penalty{} - -2.0 to 2.0; positive values discourage reusing any token already in the output",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "  {}frequency-penalty{} - -2.0 to 2.0; positive values discourage tokens the more often they already appear",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "\nPress Tab to complete commands, setting names and values, and model names."
+                );
+                println!("Just type any other message to chat with Gemini!");
+                println!(
+                    "Start it with {}@model:<name>{} to use another model for that prompt only.",
+                    t.keyword, t.reset
+                );
+                println!(
+                    "Add {}@file:<path>{} to include a file's contents in the prompt.",
+                    t.keyword, t.reset
+                );
+                continue;
+            }
+            "set" => {
+                settings.print();
+                continue;
+            }
+            _ if input.starts_with("set ") => {
+                // The value is the rest of the line, since theme names can contain spaces.
+                match input["set ".len()..].trim().split_once(char::is_whitespace) {
+                    Some((key, value)) => match settings.apply(key, value.trim()) {
+                        Ok(()) => println!("{}{} = {}{}", t.dim, key, value.trim(), t.reset),
+                        Err(e) => eprintln!("{}Error:{} {}", t.red, t.reset, e),
+                    },
+                    None => eprintln!("{}Usage:{} set <key> <value>", t.red, t.reset),
+                }
+                continue;
+            }
+            "model" => {
+                println!("{}{}{}", t.keyword, settings.model_label(), t.reset);
+                continue;
+            }
+            _ if input.starts_with("model ") => {
+                let name = input["model ".len()..].trim();
+                match resolve_model_name(name, &settings.aliases) {
+                    Ok(model) => {
+                        settings.model = model;
+                        println!("{}Now using {}{}", t.dim, settings.model_label(), t.reset);
+                        if let Some(cache) = &conversation.cache {
+                            if cache.model != settings.model {
+                                println!(
+                                    "{}The cached context only applies to {}.{}",
+                                    t.dim, cache.model, t.reset
+                                );
+                            }
+                        }
+                    }
+                    Err(e) => eprintln!("{}Error:{} {}", t.red, t.reset, e),
+                }
+                continue;
+            }
+            "system" => {
+                match &conversation.system_instruction {
+                    Some(text) => println!("{}", text),
+                    None => println!("{}No system instruction set.{}", t.dim, t.reset),
+                }
+                continue;
+            }
+            _ if input.starts_with("system ") => {
+                let text = input["system ".len()..].trim();
+                if text == "off" {
+                    conversation.system_instruction = None;
+                    println!("{}System instruction cleared.{}", t.dim, t.reset);
+                } else {
+                    conversation.system_instruction = Some(text.to_string());
+                    println!("{}System instruction set.{}", t.dim, t.reset);
+                }
+                continue;
+            }
+            _ if input == "retry" || input.starts_with("retry ") => {
+                let (args, diff) = take_diff_flag(&input["retry".len()..]);
+                if !args.is_empty() {
+                    eprintln!("{}Usage:{} retry [--diff]", t.red, t.reset);
+                    continue;
+                }
+                if conversation.turns.last().map(|turn| turn.role) != Some(Role::Model) {
+                    println!("{}Nothing to retry yet.{}", t.dim, t.reset);
+                    continue;
+                }
+                // Ask again without the previous answer, restoring it if that fails.
+                let previous = conversation.turns.pop().unwrap();
+                let sent = Instant::now();
+                let reply = ask(
+                    &client,
+                    &api_key,
+                    &conversation.request(&settings),
+                    &settings,
+                    quiet,
+                )
+                .await;
+                match reply {
+                    Ok(reply) => {
+                        if diff {
+                            print_word_diff(&previous.text(), &reply);
+                        }
+                        conversation
+                            .turns
+                            .push(Content::reply(&reply, &settings, sent.elapsed()));
+                        conversation.unsaved = true;
+                    }
+                    Err(e) => {
+                        conversation.turns.push(previous);
+                        forget_expired_cache(&mut conversation, &*e);
+                    }
+                }
+                continue;
+            }
+            _ if input == "amend" || input.starts_with("amend ") => {
+                let text = input["amend".len()..].trim();
+                if text.is_empty() {
+                    eprintln!("{}Usage:{} amend <new text>", t.red, t.reset);
+                    continue;
+                }
+                let turns = &conversation.turns;
+                if turns.len() < 2
+                    || turns[turns.len() - 1].role != Role::Model
+                    || turns[turns.len() - 2].role != Role::User
+                {
+                    println!("{}Nothing to amend yet.{}", t.dim, t.reset);
+                    continue;
+                }
+                let prompt = match expand_file_directives(text) {
+                    Ok(prompt) => settings.wrap_prompt(prompt),
+                    Err(e) => {
+                        eprintln!("{}Error:{} {}", t.red, t.reset, e);
+                        continue;
+                    }
+                };
+                if !confirm("Discard the last question and its answer?") {
+                    println!("{}Cancelled.{}", t.dim, t.reset);
+                    continue;
+                }
+                // Ask in place of the last exchange, restoring it if that fails.
+                let previous = conversation.turns.split_off(conversation.turns.len() - 2);
+                conversation
+                    .turns
+                    .push(Content::new(Role::User, &prompt).stamped());
+                let sent = Instant::now();
+                let reply = ask(
+                    &client,
+                    &api_key,
+                    &conversation.request(&settings),
+                    &settings,
+                    quiet,
+                )
+                .await;
+                match reply {
+                    Ok(reply) => {
+                        conversation
+                            .turns
+                            .push(Content::reply(&reply, &settings, sent.elapsed()));
+                        conversation.unsaved = true;
+                        last_prompt = Some(text.to_string());
+                    }
+                    Err(e) => {
+                        conversation.turns.pop();
+                        conversation.turns.extend(previous);
+                        forget_expired_cache(&mut conversation, &*e);
+                    }
+                }
+                continue;
+            }
+            _ if input == "compare" || input.starts_with("compare ") => {
+                let (args, diff) = take_diff_flag(&input["compare".len()..]);
+                let [model] = args[..] else {
+                    eprintln!("{}Usage:{} compare <model> [--diff]", t.red, t.reset);
+                    continue;
+                };
+                let model = match resolve_model_name(model, &settings.aliases) {
+                    Ok(model) => model,
+                    Err(e) => {
+                        eprintln!("{}Error:{} {}", t.red, t.reset, e);
+                        continue;
+                    }
+                };
+                let Some((last, earlier)) = conversation.turns.split_last() else {
+                    println!("{}Nothing to compare yet.{}", t.dim, t.reset);
+                    continue;
+                };
+                if last.role != Role::Model {
+                    println!("{}Nothing to compare yet.{}", t.dim, t.reset);
+                    continue;
+                }
+
+                // Ask the other model the same question; the conversation keeps the original answer.
+                println!("{}Asking {}...{}", t.dim, model, t.reset);
+                let previous_model = std::mem::replace(&mut settings.model, model);
+                let request = GeminiRequest {
+                    contents: recent_turns(earlier, settings.history_limit),
+                    ..conversation.request(&settings)
+                };
+                let reply = ask(&client, &api_key, &request, &settings, quiet).await;
+                settings.model = previous_model;
+                if let (Ok(reply), true) = (reply, diff) {
+                    print_word_diff(&last.text(), &reply);
+                }
+                continue;
+            }
+            "cache-context" => {
+                match &conversation.cache {
+                    Some(cache) => println!(
+                        "{}Using cached context {} for {}{}",
+                        t.dim, cache.name, cache.model, t.reset
+                    ),
+                    None => println!("{}No cached context.{}", t.dim, t.reset),
+                }
+                continue;
+            }
+            "cache-context off" => {
+                match conversation.cache.take() {
+                    Some(cache) => {
+                        // The cache expires on its own, so failing to delete it early is harmless.
+                        if let Err(e) = delete_cache(&client, &api_key, &cache.name).await {
+                            eprintln!("{}Warning:{} {}", t.yellow, t.reset, e);
+                        }
+                        println!("{}Cached context dropped.{}", t.dim, t.reset);
+                    }
+                    None => println!("{}No cached context.{}", t.dim, t.reset),
+                }
+                continue;
+            }
+            _ if input.starts_with("cache-context ") => {
+                let text = match expand_file_directives(&input["cache-context ".len()..]) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        eprintln!("{}Error:{} {}", t.red, t.reset, e);
+                        continue;
+                    }
+                };
+                println!(
+                    "{}Caching context for {}...{}",
+                    t.dim, settings.model, t.reset
+                );
+                match create_cache(
+                    &client,
+                    &api_key,
+                    &settings.model,
+                    &text,
+                    conversation.system_instruction.as_deref(),
+                )
+                .await
+                {
+                    Ok(name) => {
+                        println!("{}Cached as {}{}", t.dim, name, t.reset);
+                        conversation.cache = Some(CachedContext {
+                            name,
+                            model: settings.model.clone(),
+                        });
+                    }
+                    Err(e) => eprintln!("{}Error:{} {}", t.red, t.reset, e),
+                }
+                continue;
+            }
+            "history" => {
+                print_history(&conversation);
+                continue;
+            }
+            "history-clear" => {
+                if !confirm("Delete the prompt history of all sessions?") {
+                    continue;
+                }
+                let cleared = editor
+                    .clear_history()
+                    .map_err(|e| e.to_string())
+                    .and_then(|()| match fs::remove_file(&history_file) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.to_string()),
+                        _ => Ok(()),
+                    });
+                match cleared {
+                    Ok(()) => println!("{}Prompt history cleared.{}", t.dim, t.reset),
+                    Err(e) => eprintln!("{}Error:{} {}", t.red, t.reset, e),
+                }
+                continue;
+            }
+            "links" => {
+                let links = last_response_links(&conversation);
+                if links.is_empty() {
+                    println!("{}No links in the last response.{}", t.dim, t.reset);
+                }
+                for (i, link) in links.iter().enumerate() {
+                    println!(
+                        "  {}{}.{} {}{}{}",
+                        t.dim,
+                        i + 1,
+                        t.reset,
+                        t.blue,
+                        link,
+                        t.reset
+                    );
+                }
+                continue;
+            }
+            "rerender" => {
+                match conversation
+                    .turns
+                    .iter()
+                    .rev()
+                    .find(|turn| turn.role == Role::Model)
+                {
+                    Some(turn) => {
+                        if !quiet {
+                            println!("{}Gemini:{}", t.bold, t.reset);
+                        }
+                        render_response(&turn.text(), &settings);
+                    }
+                    None => println!("{}No response to re-render yet.{}", t.dim, t.reset),
+                }
+                continue;
+            }
+            "write" => {
+                eprintln!("{}Usage:{} write [n] <path>", t.red, t.reset);
+                continue;
+            }
+            _ if input
+                .strip_prefix("write ")
+                .is_some_and(|args| parse_write_args(args).is_some()) =>
+            {
+                let Some((number, path)) = parse_write_args(&input["write ".len()..]) else {
+                    continue;
+                };
+                let blocks = conversation
+                    .turns
+                    .iter()
+                    .rev()
+                    .find(|turn| turn.role == Role::Model)
+                    .map(|turn| code_blocks(&turn.text()))
+                    .unwrap_or_default();
+                let block = match number {
+                    None => blocks.last(),
+                    Some(number) => number.checked_sub(1).and_then(|index| blocks.get(index)),
+                };
+                let Some((language, code)) = block else {
+                    match blocks.len() {
+                        0 => println!("{}No code blocks in the last response.{}", t.dim, t.reset),
+                        count => eprintln!(
+                            "{}Error:{} The last response has {} code block{}",
+                            t.red,
+                            t.reset,
+                            count,
+                            if count == 1 { "" } else { "s" }
+                        ),
+                    }
+                    continue;
+                };
+                let path = code_block_path(path, language);
+                let lines = code.lines().count();
+                let question = if path.exists() {
+                    format!("{} already exists. Overwrite it?", path.display())
+                } else {
+                    format!(
+                        "Write {} line{} to {}?",
+                        lines,
+                        if lines == 1 { "" } else { "s" },
+                        path.display()
+                    )
+                };
+                if !confirm(&question) {
+                    continue;
+                }
+                match fs::write(&path, code) {
+                    Ok(()) => println!(
+                        "{}Wrote {} line{} to {}.{}",
+                        t.dim,
+                        lines,
+                        if lines == 1 { "" } else { "s" },
+                        path.display(),
+                        t.reset
+                    ),
+                    Err(e) => eprintln!(
+                        "{}Error:{} Could not write {}: {}",
+                        t.red,
+                        t.reset,
+                        path.display(),
+                        e
+                    ),
+                }
+                continue;
+            }
+            "run" | "run --" => {
+                eprintln!("{}Usage:{} run [--] <shell command>", t.red, t.reset);
+                continue;
+            }
+            // "run me through how lifetimes work" is a prompt, not the command.
+            _ if input
+                .strip_prefix("run ")
+                .is_some_and(|args| run_command(args).is_some()) =>
+            {
+                let command = run_command(&input["run ".len()..]).unwrap();
+                if !cli.allow_exec {
+                    eprintln!(
+                        "{}Error:{} 'run' executes shell commands, so it only works when the REPL is started with --allow-exec",
+                        t.red, t.reset
+                    );
+                    continue;
+                }
+                let Some(response) = conversation
+                    .turns
+                    .iter()
+                    .rev()
+                    .find(|turn| turn.role == Role::Model)
+                    .map(Content::text)
+                else {
+                    println!("{}No response to run yet.{}", t.dim, t.reset);
+                    continue;
+                };
+                let code = single_code_block(&response);
+                let input = code.as_deref().unwrap_or(&response);
+                println!("{}{}{}", t.dim, input.trim_end(), t.reset);
+                let source = if code.is_some() {
+                    "the code block above"
+                } else {
+                    "the response above"
+                };
+                if !confirm(&format!("Run '{}' with {} on stdin?", command, source)) {
+                    continue;
+                }
+                match run_with_response(command, input, &response) {
+                    Ok(output) => {
+                        io::stdout().write_all(&output.stdout).unwrap();
+                        io::stderr().write_all(&output.stderr).unwrap();
+                        if !output.status.success() {
+                            eprintln!(
+                                "{}'{}' exited with {}{}",
+                                t.dim, command, output.status, t.reset
+                            );
+                        }
+                    }
+                    Err(e) => eprintln!(
+                        "{}Error:{} Could not run '{}': {}",
+                        t.red, t.reset, command, e
+                    ),
+                }
+                continue;
+            }
+            // "open questions in physics" is a prompt, not the command.
+            _ if input == "open"
+                || input
+                    .strip_prefix("open ")
+                    .is_some_and(|n| n.trim().parse::<usize>().is_ok_and(|n| n > 0)) =>
+            {
+                let links = last_response_links(&conversation);
+                let index = match input["open".len()..].trim() {
+                    "" => links.len(),
+                    n => n.parse().unwrap(),
+                };
+                if links.is_empty() {
+                    println!("{}No links in the last response.{}", t.dim, t.reset);
+                    continue;
+                }
+                let Some(url) = links.get(index - 1) else {
+                    eprintln!(
+                        "{}Error:{} No link #{}; the last response has {}, see 'links'",
+                        t.red,
+                        t.reset,
+                        index,
+                        links.len()
+                    );
+                    continue;
+                };
+                match open_in_browser(url) {
+                    Ok(()) => println!("{}Opened {}{}", t.dim, url, t.reset),
+                    Err(e) => eprintln!("{}Error:{} {}", t.red, t.reset, e),
+                }
+                continue;
+            }
+            "fork" => {
+                forks.push((conversation.turns.clone(), conversation.unsaved));
+                println!(
+                    "{}Forked the conversation; 'back' returns to it as it is now.{}",
+                    t.dim, t.reset
+                );
+                continue;
+            }
+            "back" | "unfork" => {
+                match forks.pop() {
+                    Some((turns, unsaved)) => {
+                        conversation.turns = turns;
+                        conversation.unsaved = unsaved;
+                        println!(
+                            "{}Back to the conversation before the fork ({} messages).{}",
+                            t.dim,
+                            conversation.turns.len(),
+                            t.reset
+                        );
+                    }
+                    None => println!("{}Not in a fork.{}", t.dim, t.reset),
+                }
+                continue;
+            }
+            "session" => {
+                let mut names: Vec<(&str, &str, bool)> = sessions
+                    .iter()
+                    .map(|session| (session.name.as_str(), session.color, false))
+                    .chain([(session_name.as_str(), session_color, true)])
+                    .collect();
+                names.sort();
+                for (name, color, active) in names {
+                    let marker = if active { "*" } else { " " };
+                    println!("{} {}{}{}", marker, color, name, t.reset);
+                }
+                continue;
+            }
+            _ if input.starts_with("session ") => {
+                let args: Vec<&str> = input["session".len()..].split_whitespace().collect();
+                let (new, name) = match args[..] {
+                    ["new", name] => (true, name),
+                    ["switch", name] => (false, name),
+                    _ => {
+                        eprintln!(
+                            "{}Usage:{} session [new <name> | switch <name>]",
+                            t.red, t.reset
+                        );
+                        continue;
+                    }
+                };
+                let existing = sessions.iter().position(|session| session.name == name);
+                let next = match (new, existing) {
+                    _ if name == session_name => {
+                        eprintln!("{}Error:{} Already in session '{}'", t.red, t.reset, name);
+                        continue;
+                    }
+                    (true, Some(_)) => {
+                        eprintln!(
+                            "{}Error:{} There is already a session named '{}'; use 'session switch {}'",
+                            t.red, t.reset, name, name
+                        );
+                        continue;
+                    }
+                    (false, None) => {
+                        eprintln!(
+                            "{}Error:{} No session named '{}'; 'session' lists them",
+                            t.red, t.reset, name
+                        );
+                        continue;
+                    }
+                    (false, Some(index)) => sessions.remove(index),
+                    // A new session keeps the settings and system instruction
+                    // of the one it was started from, but none of its turns.
+                    (true, None) => {
+                        let colors = session_colors();
+                        NamedSession {
+                            name: name.to_string(),
+                            color: colors[(sessions.len() + 1) % colors.len()],
+                            conversation: Conversation {
+                                system_instruction: conversation.system_instruction.clone(),
+                                ..Conversation::default()
+                            },
+                            settings: settings.clone(),
+                            forks: Vec::new(),
+                        }
+                    }
+                };
+                sessions.push(NamedSession {
+                    name: std::mem::replace(&mut session_name, next.name),
+                    color: std::mem::replace(&mut session_color, next.color),
+                    conversation: std::mem::replace(&mut conversation, next.conversation),
+                    settings: std::mem::replace(&mut settings, next.settings),
+                    forks: std::mem::replace(&mut forks, next.forks),
+                });
+                let verb = if new { "Started" } else { "Switched to" };
+                println!(
+                    "{}{} session {}{}{}{} ({} messages, model {}).{}",
+                    t.dim,
+                    verb,
+                    t.reset,
+                    session_color,
+                    session_name,
+                    t.dim,
+                    conversation.turns.len(),
+                    settings.model,
+                    t.reset
+                );
+                continue;
+            }
+            // Not listed in help: a diagnostic for comparing models and networks.
+            _ if input == "bench" || input.starts_with("bench ") => {
+                let args: Vec<&str> = input["bench".len()..].split_whitespace().collect();
+                let parsed = match args[..] {
+                    [runs] => runs.parse().ok().map(|runs| (runs, 1)),
+                    [runs, concurrency] => runs.parse().ok().zip(concurrency.parse().ok()),
+                    _ => None,
+                };
+                match parsed {
+                    Some((runs, concurrency)) if runs > 0 && concurrency > 0 => {
+                        run_bench(&client, &api_key, &settings, runs, concurrency).await;
+                    }
+                    _ => eprintln!("{}Usage:{} bench <runs> [concurrency]", t.red, t.reset),
+                }
+                continue;
+            }
+            "attach" => {
+                eprintln!("{}Usage:{} attach <path>", t.red, t.reset);
+                continue;
+            }
+            _ if input.starts_with("attach ") => {
+                let path = Path::new(input["attach ".len()..].trim());
+                match upload_file(&client, &api_key, path, quiet).await {
+                    Ok(part) => {
+                        attachments.push(part);
+                        println!(
+                            "{}Attached {}; it will be sent with your next prompt.{}",
+                            t.dim,
+                            path.display(),
+                            t.reset
+                        );
+                    }
+                    Err(e) => eprintln!("{}Error:{} {}", t.red, t.reset, e),
+                }
+                continue;
+            }
+            // "summarize this article: ..." is a prompt, not the command.
+            _ if input == "summarize"
+                || input
+                    .strip_prefix("summarize ")
+                    .is_some_and(|keep| keep.trim().parse::<usize>().is_ok()) =>
+            {
+                let keep = input["summarize".len()..]
+                    .trim()
+                    .parse::<usize>()
+                    .unwrap_or(SUMMARIZE_KEEP_TURNS);
+                println!("{}Summarizing...{}", t.dim, t.reset);
+                match summarize_history(&client, &api_key, &settings.model, &mut conversation, keep)
+                    .await
+                {
+                    Ok(0) => println!("{}Nothing to summarize yet.{}", t.dim, t.reset),
+                    Ok(count) => println!(
+                        "{}Replaced {} turns with a summary, see 'history'.{}",
+                        t.dim, count, t.reset
+                    ),
+                    Err(e) => eprintln!("{}Error:{} {}", t.red, t.reset, e),
+                }
+                continue;
+            }
+            "secret" => {
+                let prompt = match rpassword::prompt_password("Secret prompt (hidden): ") {
+                    Ok(prompt) => prompt,
+                    Err(e) => {
+                        eprintln!("{}Error:{} {}", t.red, t.reset, e);
+                        continue;
+                    }
+                };
+                if prompt.trim().is_empty() {
+                    println!("{}Nothing sent.{}", t.dim, t.reset);
+                    continue;
+                }
+                conversation
+                    .turns
+                    .push(Content::redacted(prompt.trim()).stamped());
+                let sent = Instant::now();
+                let reply = ask(
+                    &client,
+                    &api_key,
+                    &conversation.request(&settings),
+                    &settings,
+                    quiet,
+                )
+                .await;
+                match reply {
+                    Ok(reply) => {
+                        conversation
+                            .turns
+                            .push(Content::reply(&reply, &settings, sent.elapsed()));
+                        conversation.unsaved = true;
+                    }
+                    Err(e) => {
+                        conversation.turns.pop();
+                        forget_expired_cache(&mut conversation, &*e);
+                    }
+                }
+                continue;
+            }
+            "reset" => {
+                conversation.turns.clear();
+                conversation.unsaved = false;
+                println!("{}Conversation cleared.{}", t.dim, t.reset);
+                continue;
+            }
+            // "save the whales: which charities work best?" is a prompt, not the command.
+            _ if input == "save"
+                || input.strip_prefix("save ").is_some_and(|name| {
+                    !name.trim().contains(char::is_whitespace)
+                        && validate_session_name(name.trim()).is_ok()
+                }) =>
+            {
+                if conversation.turns.is_empty() {
+                    println!("{}Nothing to save yet.{}", t.dim, t.reset);
+                    continue;
+                }
+                let name = input["save".len()..].trim();
+                let name = (!name.is_empty()).then_some(name);
+                match save_session(&conversation, name) {
+                    Ok(path) => {
+                        conversation.unsaved = false;
+                        println!(
+                            "{}Saved conversation to {}{}",
+                            t.dim,
+                            path.display(),
+                            t.reset
+                        );
+                    }
+                    Err(e) => eprintln!("{}Error:{} {}", t.red, t.reset, e),
+                }
+                continue;
+            }
+            "sessions" => {
+                print_sessions();
+                continue;
+            }
+            _ if input == "replay" || input.starts_with("replay ") => {
+                let args = input["replay".len()..].trim();
+                // A trailing number is the delay, so file names may contain spaces.
+                let (name, delay) = match args.rsplit_once(char::is_whitespace) {
+                    Some((name, delay)) => match delay.parse::<f64>() {
+                        Ok(delay) => (name.trim(), Duration::try_from_secs_f64(delay).ok()),
+                        Err(_) => (args, Some(REPLAY_DELAY)),
+                    },
+                    None => (args, Some(REPLAY_DELAY)),
+                };
+                let (false, Some(delay)) = (name.is_empty(), delay) else {
+                    eprintln!("{}Usage:{} replay <file> [seconds]", t.red, t.reset);
+                    continue;
+                };
+                if let Err(e) = replay_session(&find_session(name), delay, &settings, quiet).await {
+                    eprintln!("{}Error:{} {}", t.red, t.reset, e);
+                }
+                continue;
+            }
+            "resume" => {
+                eprintln!("{}Usage:{} resume <n>", t.red, t.reset);
+                continue;
+            }
+            // "resumes for engineers" is a prompt, not the command.
+            _ if input
+                .strip_prefix("resume ")
+                .is_some_and(|n| n.trim().parse::<usize>().is_ok()) =>
+            {
+                let index = match input["resume ".len()..].trim().parse::<usize>() {
+                    Ok(index) if index > 0 => index,
+                    _ => {
+                        eprintln!("{}Usage:{} resume <n>", t.red, t.reset);
+                        continue;
+                    }
+                };
+                match list_sessions().map(|sessions| sessions.into_iter().nth(index - 1)) {
+                    Ok(Some(session)) => match load_session(&session.path) {
+                        Ok(loaded) => {
+                            conversation = Conversation {
+                                cache: conversation.cache.take(),
+                                ..loaded
+                            };
+                            // Forks belong to the conversation that was replaced.
+                            forks.clear();
+                            println!(
+                                "{}Resumed {} ({} messages){}",
+                                t.dim,
+                                session.path.display(),
+                                conversation.turns.len(),
+                                t.reset
+                            );
+                        }
+                        Err(e) => eprintln!("{}Error:{} {}", t.red, t.reset, e),
+                    },
+                    Ok(None) => eprintln!(
+                        "{}Error:{} No saved session #{}, see 'sessions'",
+                        t.red, t.reset, index
+                    ),
+                    Err(e) => eprintln!("{}Error:{} {}", t.red, t.reset, e),
+                }
+                continue;
+            }
+            _ => {
+                let (name, args) = input.split_once(' ').unwrap_or((input, ""));
+                if let Some(command) = custom_commands.get(name) {
+                    let shell_command = command.run.replace("{args}", args.trim());
+                    match run_pipe(&shell_command, "") {
+                        Ok(output) => {
+                            print!("{}", output);
+                            if !output.is_empty() && !output.ends_with('\n') {
+                                println!();
+                            }
+                            if command.context && !output.trim().is_empty() {
+                                attachments.push(Part::text(&format!(
+                                    "Output of `{}`:\n```\n{}\n```\n\n",
+                                    shell_command,
+                                    output.trim_end()
+                                )));
+                                println!(
+                                    "{}The output will be sent with your next prompt.{}",
+                                    t.dim, t.reset
+                                );
+                            }
+                        }
+                        Err(e) => eprintln!("{}Error:{} {} {}", t.red, t.reset, name, e),
+                    }
+                    continue;
+                }
+                last_prompt = Some(input.to_string());
+                // `paste` sends the clipboard, after the question if there is one.
+                let pasted;
+                let input = match input.strip_prefix("paste") {
+                    Some(question) if question.is_empty() || question.starts_with(' ') => {
+                        match with_clipboard(question.trim()) {
+                            Ok(prompt) => {
+                                pasted = prompt;
+                                pasted.as_str()
+                            }
+                            Err(e) => {
+                                eprintln!("{}Error:{} {}", t.red, t.reset, e);
+                                continue;
+                            }
+                        }
+                    }
+                    _ => input,
+                };
+                // An @model: prefix swaps the model for this prompt only.
+                let (prompt, previous_model) = match parse_model_override(input) {
+                    Some((model, rest)) => {
+                        let model = match resolve_model_name(model, &settings.aliases) {
+                            Ok(model) => model,
+                            Err(e) => {
+                                eprintln!("{}Error:{} {}", t.red, t.reset, e);
+                                continue;
+                            }
+                        };
+                        if rest.is_empty() {
+                            eprintln!("{}Usage:{} @model:<name> <prompt>", t.red, t.reset);
+                            continue;
+                        }
+                        let previous = std::mem::replace(&mut settings.model, model);
+                        (rest, Some(previous))
+                    }
+                    None => (input, None),
+                };
+                let prompt = match expand_file_directives(prompt) {
+                    Ok(prompt) => settings.wrap_prompt(prompt),
+                    Err(e) => {
+                        eprintln!("{}Error:{} {}", t.red, t.reset, e);
+                        if let Some(previous) = previous_model {
+                            settings.model = previous;
+                        }
+                        continue;
+                    }
+                };
+
+                conversation.turns.push(
+                    Content::with_attachments(std::mem::take(&mut attachments), &prompt).stamped(),
+                );
+                if settings.confirm_cost
+                    && !confirm_cost(&client, &api_key, &conversation.turns, &settings).await
+                {
+                    attachments.extend(conversation.turns.pop().unwrap().into_attachments());
+                    println!("{}Cancelled.{}", t.dim, t.reset);
+                } else {
+                    let sent = Instant::now();
+                    let reply = ask(
+                        &client,
+                        &api_key,
+                        &conversation.request(&settings),
+                        &settings,
+                        quiet,
+                    )
+                    .await;
+                    match reply {
+                        Ok(reply) => {
+                            conversation.turns.push(Content::reply(
+                                &reply,
+                                &settings,
+                                sent.elapsed(),
+                            ));
+                            conversation.unsaved = true;
+                        }
+                        Err(e) => {
+                            attachments
+                                .extend(conversation.turns.pop().unwrap().into_attachments());
+                            forget_expired_cache(&mut conversation, &*e);
+                        }
+                    }
+                }
+
+                if let Some(previous) = previous_model {
+                    settings.model = previous;
+                }
+            }
+        }
+    }
+
+    // Any typewriter and spinner have finished by now; make sure their
+    // output is out before the process exits.
+    println!("{}Goodbye!{}", t.yellow, t.reset);
+    io::stdout().flush()?;
+    io::stderr().flush()?;
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recent_turns_starts_at_a_user_turn() {
+        let turns: Vec<Content> = ["q1", "a1", "q2", "a2", "q3"]
+            .iter()
+            .enumerate()
+            .map(|(i, text)| {
+                let role = if i % 2 == 0 { Role::User } else { Role::Model };
+                Content::new(role, text)
+            })
+            .collect();
+        let texts = |limit| -> Vec<String> {
+            recent_turns(&turns, limit)
+                .iter()
+                .map(Content::text)
+                .collect()
+        };
+        assert_eq!(texts(None).len(), 5);
+        assert_eq!(texts(Some(3)), ["q2", "a2", "q3"]);
+        assert_eq!(texts(Some(4)), ["q2", "a2", "q3"]);
+        assert_eq!(texts(Some(10)).len(), 5);
+    }
+
+    /// Feeds `chunks` to `StreamEvents`, returning the text pieces and the reply text.
+    fn stream_in_chunks<'a>(chunks: impl Iterator<Item = &'a [u8]>) -> (Vec<String>, String) {
+        let mut events = StreamEvents::default();
+        let mut pieces = Vec::new();
+        let mut on_part = |part: &ResponsePart| pieces.push(part.text.clone());
+        for chunk in chunks {
+            events.push(chunk, &mut on_part).unwrap();
+        }
+        events.finish(&mut on_part).unwrap();
+        (pieces, events.reply.text)
+    }
+
+    #[test]
+    fn stream_events_survive_any_chunk_boundary() {
+        let body = include_bytes!("../tests/fixtures/stream_max_tokens.sse");
+        let expected = stream_in_chunks([&body[..]].into_iter());
+        assert_eq!(expected.0.len(), 2);
+        assert_eq!(stream_in_chunks(body.chunks(1)), expected);
+        for split in 1..body.len() {
+            let (head, tail) = body.split_at(split);
+            assert_eq!(
+                stream_in_chunks([head, tail].into_iter()),
+                expected,
+                "split at {}",
+                split
+            );
+        }
+
+        // Multi-byte characters split between chunks, an event spread over
+        // two data lines, a comment, and no blank line at the very end.
+        let body = "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"h\u{e9}llo \u{2713}\"}]}}]}\n\n\
+                    : keep-alive\n\n\
+                    data: {\"candidates\":[{\"content\":\n\
+                    data: {\"parts\":[{\"text\":\" \u{1F44B}\"}]}}]}\n";
+        let (pieces, text) = stream_in_chunks(body.as_bytes().chunks(1));
+        assert_eq!(pieces, ["h\u{e9}llo \u{2713}", " \u{1F44B}"]);
+        assert_eq!(text, "h\u{e9}llo \u{2713} \u{1F44B}");
+    }
+
+    #[test]
+    fn stream_keeps_finish_reason_and_usage_from_the_last_chunk() {
+        let body = include_bytes!("../tests/fixtures/stream_max_tokens.sse");
+        let mut events = StreamEvents::default();
+        let mut pieces = Vec::new();
+        // Uneven chunks, so events are split across them like on the wire.
+        for chunk in body.chunks(37) {
+            events
+                .push(chunk, &mut |part| pieces.push(part.text.clone()))
+                .unwrap();
+        }
+        assert_eq!(
+            pieces,
+            ["The three laws of", " motion are: first, an object"]
+        );
+        let reply = events.reply.into_result().unwrap();
+        assert_eq!(reply.text, "The three laws of motion are: first, an object");
+        assert_eq!(reply.finish_reason.as_deref(), Some("MAX_TOKENS"));
+        assert_eq!(
+            reply.usage,
+            Some(UsageMetadata {
+                prompt_token_count: 9,
+                candidates_token_count: 12,
+                total_token_count: 21,
+            })
+        );
+    }
+
+    #[test]
+    fn thoughts_are_kept_apart_from_the_answer() {
+        let response: GeminiResponse = serde_json::from_str(
+            r#"{"candidates": [{"content": {"parts": [
+                {"text": "Weighing the options.", "thought": true},
+                {"text": "Use a "},
+                {"text": "HashMap."}
+            ]}}]}"#,
+        )
+        .unwrap();
+        let mut reply = Reply::default();
+        let thoughts: Vec<bool> = reply
+            .add(&response)
+            .iter()
+            .map(|part| part.thought)
+            .collect();
+        assert_eq!(thoughts, [true, false, false]);
+        assert_eq!(reply.thoughts, "Weighing the options.");
+        assert_eq!(reply.text, "Use a HashMap.");
+
+        let settings = Settings {
+            show_thoughts: true,
+            ..Settings::default()
+        };
+        let json = serde_json::to_value(settings.generation_config()).unwrap();
+        assert_eq!(
+            json["thinkingConfig"],
+            serde_json::json!({"includeThoughts": true})
+        );
+    }
+
+    #[test]
+    fn retry_jitter_stays_within_the_backoff() {
+        let backoff = Duration::from_secs(2);
+        assert_eq!(jittered(backoff, Jitter::None, 0.3), backoff);
+        assert_eq!(jittered(backoff, Jitter::Full, 0.0), Duration::ZERO);
+        assert_eq!(jittered(backoff, Jitter::Full, 0.5), Duration::from_secs(1));
+        assert_eq!(
+            jittered(backoff, Jitter::Equal, 0.0),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            jittered(backoff, Jitter::Equal, 0.5),
+            Duration::from_millis(1500)
+        );
+        for _ in 0..100 {
+            assert!((0.0..1.0).contains(&random_fraction()));
+        }
+        let config: Config = toml::from_str("retry_jitter = \"equal\"").unwrap();
+        assert_eq!(config.retry_jitter, Jitter::Equal);
+    }
+
+    #[test]
+    fn api_key_is_saved_at_the_top_level() {
+        assert_eq!(with_api_key("", "k1"), "api_key = \"k1\"\n");
+        let text = "model = \"gemini-2.5-pro\"\n\n[commands]\napi_key = \"tool\"\n";
+        assert_eq!(
+            with_api_key(text, "k2"),
+            "api_key = \"k2\"\nmodel = \"gemini-2.5-pro\"\n\n[commands]\napi_key = \"tool\"\n"
+        );
+        let text = "api_key = \"old\"\ntheme = \"Nord\"\n";
+        assert_eq!(
+            with_api_key(text, "new"),
+            "api_key = \"new\"\ntheme = \"Nord\"\n"
+        );
+    }
+
+    #[test]
+    fn prompt_wrapper_needs_the_placeholder() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.wrap_prompt("Why?".to_string()), "Why?");
+        settings
+            .apply("wrapper", "Answer concisely. Question: {prompt}")
+            .unwrap();
+        assert_eq!(
+            settings.wrap_prompt("Why?".to_string()),
+            "Answer concisely. Question: Why?"
+        );
+        assert!(settings.apply("wrapper", "Answer concisely.").is_err());
+        settings.apply("wrapper", "none").unwrap();
+        assert_eq!(settings.prompt_wrapper, None);
+    }
+
+    #[test]
+    fn quota_exhaustion_is_told_apart_from_a_rate_limit() {
+        let body = |quota_id: &str| {
+            format!(
+                r#"{{"error": {{"code": 429, "message": "You exceeded your current quota.", "status": "RESOURCE_EXHAUSTED",
+                "details": [
+                  {{"@type": "type.googleapis.com/google.rpc.QuotaFailure", "violations": [{{"quotaId": "{}"}}]}},
+                  {{"@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "23s"}}
+                ]}}}}"#,
+                quota_id
+            )
+        };
+        let minute = api_error(
+            429,
+            body("GenerateRequestsPerMinutePerProjectPerModel-FreeTier"),
+        );
+        assert_eq!(
+            minute.rate_limit(),
+            Some(RateLimit::Temporary(Some(Duration::from_secs(23))))
+        );
+        let day = api_error(
+            429,
+            body("GenerateRequestsPerDayPerProjectPerModel-FreeTier"),
+        );
+        assert_eq!(day.rate_limit(), Some(RateLimit::Exhausted));
+        assert!(day.to_string().contains("quota"));
+        assert_eq!(rate_limit_wait(&day, 0, Jitter::None), None);
+
+        let plain = api_error(429, "Too Many Requests".to_string());
+        assert_eq!(plain.rate_limit(), Some(RateLimit::Temporary(None)));
+        assert_eq!(
+            rate_limit_wait(&plain, 0, Jitter::None),
+            Some(NETWORK_RETRY_DELAY)
+        );
+        assert_eq!(api_error(500, String::new()).rate_limit(), None);
+    }
+
+    #[test]
+    fn uncertain_spans_follow_low_probability_tokens() {
+        let token = |token: &str, probability: f64| TokenLogprob {
+            token: token.to_string(),
+            log_probability: f64::ln(probability),
+        };
+        let text = "1. The capital is Paris, probably.\n- Lyon";
+        let tokens = [
+            token("1", 0.2),
+            token(".", 0.9),
+            token(" The", 0.9),
+            token(" capital", 0.9),
+            token(" is", 0.9),
+            token(" Paris", 0.3),
+            token(",", 0.9),
+            token(" probably", 0.9),
+            token(".", 0.9),
+            token("\n", 0.9),
+            token("-", 0.1),
+            token(" Lyon", 0.4),
+        ];
+        let spans = uncertain_spans(text, &tokens);
+        let words: Vec<&str> = spans.iter().map(|span| &text[span.clone()]).collect();
+        // The list markers start their lines, so only the words are marked.
+        assert_eq!(words, ["Paris", "Lyon"]);
+    }
+
+    #[test]
+    fn model_aliases_resolve_to_full_names() {
+        let mut settings = Settings {
+            aliases: model_aliases(HashMap::from([
+                (
+                    "flash".to_string(),
+                    "gemini-2.5-flash-lite-preview-06-17".to_string(),
+                ),
+                ("typo".to_string(), "gemni-pro".to_string()),
+            ])),
+            ..Settings::default()
+        };
+        let resolve = |name| resolve_model_name(name, &settings.aliases);
+        assert_eq!(resolve("pro").unwrap(), "gemini-2.5-pro");
+        assert_eq!(
+            resolve("flash").unwrap(),
+            "gemini-2.5-flash-lite-preview-06-17"
+        );
+        assert_eq!(resolve("gemini-2.0-flash").unwrap(), "gemini-2.0-flash");
+        assert!(resolve("typo").is_err());
+        assert!(resolve("unknown").is_err());
+
+        settings.model = resolve("flash").unwrap();
+        assert_eq!(
+            settings.model_label(),
+            "gemini-2.5-flash-lite-preview-06-17 (flash)"
+        );
+        settings.model = "gemini-2.0-flash".to_string();
+        assert_eq!(settings.model_label(), "gemini-2.0-flash");
+    }
+
+    #[test]
+    fn session_names_stay_in_the_sessions_directory() {
+        assert!(validate_session_name("work-notes").is_ok());
+        assert!(validate_session_name("v1.2").is_ok());
+        assert!(validate_session_name("../../foo").is_err());
+        assert!(validate_session_name("/tmp/x").is_err());
+        assert!(validate_session_name("a\\b").is_err());
+        assert!(validate_session_name("..").is_err());
+    }
+
+    #[test]
+    fn summary_keeps_user_and_model_turns_alternating() {
+        let summary = || Content::new(Role::User, "[Summary of the earlier conversation]\nx");
+        let roles = |turns: Vec<Content>| turns.iter().map(|turn| turn.role).collect::<Vec<_>>();
+        assert!(roles(summary_turns(summary(), Some(Role::User))) == [Role::User, Role::Model]);
+        assert!(roles(summary_turns(summary(), None)) == [Role::User, Role::Model]);
+        assert!(roles(summary_turns(summary(), Some(Role::Model))) == [Role::User]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_pipe_passes_large_input_through() {
+        let input = "0123456789abcdef\n".repeat(80_000);
+        assert_eq!(run_pipe("cat", &input).unwrap(), input);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_takes_only_commands() {
+        assert_eq!(run_command("sh -c 'wc -w'"), Some("sh -c 'wc -w'"));
+        assert_eq!(run_command("./build.sh"), Some("./build.sh"));
+        assert_eq!(run_command("-- me through it"), Some("me through it"));
+        assert_eq!(run_command("me through how lifetimes work"), None);
+        assert_eq!(run_command("sh on every CPU?"), None);
+        assert_eq!(run_command("--"), None);
+    }
+
+    #[test]
+    fn percentile_uses_nearest_rank() {
+        let values: Vec<f64> = (1..=20).map(f64::from).collect();
+        assert_eq!(percentile(&values, 50.0), 10.0);
+        assert_eq!(percentile(&values, 95.0), 19.0);
+        assert_eq!(percentile(&values[..1], 95.0), 1.0);
+        assert_eq!(percentile(&values[..3], 0.0), 1.0);
+    }
+
+    #[test]
+    fn saved_turns_keep_their_info_next_to_the_content() {
+        let turn: SavedTurn = serde_json::from_str(
+            r#"{"role":"model","parts":[{"text":"Hi"}],"timestamp":"2025-01-02T03:04:05+00:00","latencyMs":1200,"model":"gemini-2.5-pro"}"#,
+        )
+        .unwrap();
+        assert_eq!(turn.content.text(), "Hi");
+        assert_eq!(turn.info.latency_ms, Some(1200));
+        assert_eq!(turn.info.model.as_deref(), Some("gemini-2.5-pro"));
+
+        // Sessions saved before turns had info still load.
+        let turn: SavedTurn =
+            serde_json::from_str(r#"{"role":"user","parts":[{"text":"Hello"}]}"#).unwrap();
+        assert!(turn.info.timestamp.is_none());
+        assert_eq!(
+            serde_json::to_string(&turn).unwrap(),
+            r#"{"role":"user","parts":[{"text":"Hello"}]}"#
+        );
+    }
+
+    #[test]
+    fn render_html_wraps_the_markdown_in_a_page() {
+        let html = render_html("# Title\n\n```rust\nfn main() {}\n```\n");
+        assert!(html.starts_with("<!DOCTYPE html>\n"));
+        assert!(html.contains("<h1>Title</h1>"));
+        assert!(html.contains("<pre><code class=\"language-rust\">fn main() {}\n</code></pre>"));
+        assert!(html.ends_with("</body>\n</html>\n"));
+    }
+
+    #[test]
+    fn multiline_prompts_end_on_a_second_blank_line() {
+        assert!(ends_multiline_prompt("hello"));
+        assert!(!ends_multiline_prompt(""));
+        assert!(!ends_multiline_prompt("\nfirst\n"));
+        assert!(!ends_multiline_prompt("\nfirst\n\nsecond"));
+        assert!(ends_multiline_prompt("\nfirst\n\nsecond\n\n"));
+    }
+
+    #[test]
+    fn gen_config_json_keeps_known_fields_and_lists_the_rest() {
+        let (config, unknown) =
+            parse_gen_config(r#"{"temperature":0.2,"topK":40,"thinkingConfig":{"thinkingBudget":0},"candidateCount":2,"responseMimeType":"text/plain"}"#)
+                .unwrap();
+        assert_eq!(config.temperature, Some(0.2));
+        assert_eq!(config.top_k, Some(40));
+        assert_eq!(config.thinking_budget(), Some(0));
+        assert_eq!(config.candidate_count, Some(2));
+        assert_eq!(unknown, ["responseMimeType"]);
+        assert!(parse_gen_config(r#"{"temperature":3}"#).is_err());
+        assert!(parse_gen_config(r#"{"candidateCount":9}"#).is_err());
+        assert!(parse_gen_config(r#"{"topK":"forty"}"#).is_err());
+        let (config, _) = parse_gen_config(r#"{"frequencyPenalty":-0.5}"#).unwrap();
+        assert_eq!(config.frequency_penalty, Some(-0.5));
+        assert!(parse_gen_config(r#"{"presencePenalty":2.0}"#).is_err());
+        assert!(parse_gen_config("[1]").is_err());
+    }
+
+    #[test]
+    fn response_links_are_listed_once_in_order() {
+        let text = "[a](https://a.example) and <https://b.example>, then [a again](https://a.example),\n\
+                    [ref][r] and [missing][nope] and `[code](https://c.example)`.\n\n[r]: https://r.example\n";
+        assert_eq!(
+            response_links(text),
+            [
+                "https://a.example",
+                "https://b.example",
+                "https://r.example"
+            ]
+        );
+        assert!(response_links("No links here.").is_empty());
+    }
+
+    #[test]
+    fn split_words_keeps_the_whitespace_after_each_word() {
+        let text = "  Two words\n\n\x1b[1mbold\x1b[0m end ";
+        let words = split_words(text);
+        assert_eq!(
+            words,
+            ["  ", "Two ", "words\n\n", "\x1b[1mbold\x1b[0m ", "end "]
+        );
+        assert_eq!(words.concat(), text);
+        assert!(split_words("").is_empty());
+    }
+
+    #[test]
+    fn unknown_config_keys_are_found_with_suggestions() {
+        let config: toml::Table = toml::from_str(
+            r#"
+            model = "gemini-2.5-flash"
+            edit_mod = "vi"
+            colour = true
+            [models."gemini-2.5-pro"]
+            temprature = 0.2
+            top_k = 20
+            [commands]
+            status = "git status"
+            test = { run = "cargo test", contxt = true }
+            [[slow_messages]]
+            after = 5
+            mesage = "Still working"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            unknown_config_keys(&config),
+            [
+                ("colour".to_string(), None),
+                ("edit_mod".to_string(), Some("edit_mode")),
+                (
+                    "models.gemini-2.5-pro.temprature".to_string(),
+                    Some("temperature")
+                ),
+                ("commands.test.contxt".to_string(), Some("context")),
+                ("slow_messages[0].mesage".to_string(), Some("message")),
+            ]
+        );
+    }
+
+    #[test]
+    fn select_candidate_picks_by_number_or_probability() {
+        let response = |json: &str| -> GeminiResponse { serde_json::from_str(json).unwrap() };
+        let texts = |response: &GeminiResponse| -> Vec<String> {
+            let candidates = response.candidates.iter();
+            candidates
+                .map(|c| c.content.parts[0].text.clone())
+                .collect()
+        };
+        let scored = r#"{"candidates": [
+            {"content": {"parts": [{"text": "a"}]}, "avgLogprobs": -0.9},
+            {"content": {"parts": [{"text": "bb"}]}, "avgLogprobs": -0.2},
+            {"content": {"parts": [{"text": "ccc"}]}, "avgLogprobs": -0.5}
+        ]}"#;
+        let mut best = response(scored);
+        best.select_candidate(CandidateSelection::Best);
+        assert_eq!(texts(&best)[0], "bb");
+        let mut third = response(scored);
+        third.select_candidate(CandidateSelection::Number(3));
+        assert_eq!(texts(&third)[0], "ccc");
+        let mut missing = response(scored);
+        missing.select_candidate(CandidateSelection::Number(5));
+        assert_eq!(texts(&missing)[0], "a");
+
+        // Without probabilities for all of them, the longest is best.
+        let mut longest = response(
+            r#"{"candidates": [
+                {"content": {"parts": [{"text": "short"}]}, "avgLogprobs": -0.1},
+                {"content": {"parts": [{"text": "the longest"}]}}
+            ]}"#,
+        );
+        longest.select_candidate(CandidateSelection::Best);
+        assert_eq!(texts(&longest)[0], "the longest");
+        assert_eq!("best".parse(), Ok(CandidateSelection::Best));
+        assert!("0".parse::<CandidateSelection>().is_err());
+    }
+
+    #[test]
+    fn single_code_block_takes_the_only_fenced_block() {
+        let one = "Save this as hello.sh:\n\n```bash\necho hello\n```\n\nThen run it.";
+        assert_eq!(single_code_block(one).as_deref(), Some("echo hello\n"));
+        let two = "```sh\nls\n```\n\n```sh\npwd\n```\n";
+        assert_eq!(single_code_block(two), None);
+        assert_eq!(single_code_block("No code here."), None);
+    }
+
+    #[test]
+    fn code_blocks_are_saved_with_their_language_extension() {
+        let text = "```python\nprint(1)\n```\n\n```\nplain\n```\n";
+        let blocks = code_blocks(text);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0], ("python".to_string(), "print(1)\n".to_string()));
+        assert_eq!(blocks[1].0, "");
+        assert_eq!(code_block_path("hello", "python"), Path::new("hello.py"));
+        assert_eq!(code_block_path("main.rs", "python"), Path::new("main.rs"));
+        assert_eq!(code_block_path("lib", "rust,ignore"), Path::new("lib.rs"));
+        assert_eq!(code_block_path("notes", ""), Path::new("notes"));
+
+        assert_eq!(parse_write_args("hello.py"), Some((None, "hello.py")));
+        assert_eq!(
+            parse_write_args(" 2  src/main.rs "),
+            Some((Some(2), "src/main.rs"))
+        );
+        assert_eq!(parse_write_args("a python script that parses csv"), None);
+        assert_eq!(parse_write_args("tests for this"), None);
+        assert_eq!(parse_write_args("more tests"), None);
+    }
+
+    #[test]
+    fn extract_prefers_the_first_capture_group() {
+        let text = "Run this:\n\n```sh\necho hi\n```\n\nThe answer is 42.";
+        let code = Regex::new(r"```\w*\n([\s\S]*?)```").unwrap();
+        assert_eq!(extract(&code, text), Some("echo hi\n"));
+        let number = Regex::new(r"\d+").unwrap();
+        assert_eq!(extract(&number, text), Some("42"));
+        let missing = Regex::new("nowhere").unwrap();
+        assert_eq!(extract(&missing, text), None);
+    }
+
+    #[test]
+    fn base_url_must_be_https_unless_http_is_allowed() {
+        assert_eq!(
+            resolve_base_url("https://proxy.example/v1beta/", false).as_deref(),
+            Ok("https://proxy.example/v1beta")
+        );
+        assert!(resolve_base_url("http://localhost:8080", false).is_err());
+        assert_eq!(
+            resolve_base_url("http://localhost:8080", true).as_deref(),
+            Ok("http://localhost:8080")
+        );
+        assert!(resolve_base_url("ftp://proxy.example", true).is_err());
+        assert!(resolve_base_url("proxy.example", false).is_err());
+    }
+
+    #[test]
+    fn generation_config_layers_flags_over_model_defaults() {
+        let mut settings = Settings::default();
+        settings.model_defaults.insert(
+            "gemini-2.5-pro".to_string(),
+            GenerationConfig {
+                temperature: Some(0.2),
+                top_k: Some(20),
+                ..GenerationConfig::default()
+            },
+        );
+        settings.generation.temperature = Some(1.0);
+        assert!(settings.generation_config().top_k.is_none());
+
+        settings.model = "gemini-2.5-pro".to_string();
+        let generation = settings.generation_config();
+        assert_eq!(generation.temperature, Some(1.0));
+        assert_eq!(generation.top_k, Some(20));
+
+        settings.apply("temperature", "off").unwrap();
+        assert_eq!(settings.generation_config().temperature, Some(0.2));
+    }
+
+    #[test]
+    fn block_boundary_waits_for_closing_code_fence() {
+        let text = "Intro\n\n```rust\nfn main() {}\n\nlet x = 1;\n";
+        assert_eq!(last_block_boundary(text), Some("Intro\n\n".len()));
+
+        let text = "Intro\n\n```rust\nfn main() {}\n```\n\nMore";
+        assert_eq!(last_block_boundary(text), Some("Intro\n\n".len()));
+
+        let text = "Intro\n\n```rust\nfn main() {}\n```\n\nMore\n";
+        assert_eq!(
+            last_block_boundary(text),
+            Some("Intro\n\n```rust\nfn main() {}\n```\n\n".len())
+        );
+    }
 }
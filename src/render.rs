@@ -0,0 +1,2302 @@
+//! Rendering of Gemini's markdown responses for the terminal: ANSI styles,
+//! wrapping, tables, highlighted code blocks and the rest. Nothing here
+//! touches the network or the REPL, so it can be tested on its own.
+
+use std::borrow::Cow;
+use std::fmt::Write as _;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use bat::assets::HighlightingAssets;
+use bat::config::Config as BatConfig;
+use bat::controller::Controller;
+use bat::style::{StyleComponent, StyleComponents};
+use bat::PrettyPrinter;
+use pulldown_cmark::{
+    Alignment, BlockQuoteKind, BrokenLink, CodeBlockKind, CowStr, Event, HeadingLevel, LinkType,
+    Options as ParserOptions, Parser, Tag, TagEnd,
+};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// ANSI escape codes used for formatting. Every code is empty in the plain
+/// theme, so output stays readable when colors are disabled.
+pub struct Theme {
+    pub reset: &'static str,
+    pub bold: &'static str,
+    pub dim: &'static str,
+    pub italic: &'static str,
+    pub heading: &'static str,
+    pub blue: &'static str,
+    pub keyword: &'static str,
+    pub yellow: &'static str,
+    pub red: &'static str,
+    pub magenta: &'static str,
+    pub green: &'static str,
+    pub cyan: &'static str,
+    pub quote: &'static str,
+    pub strikethrough: &'static str,
+    pub inverse: &'static str,
+    pub inverse_off: &'static str,
+    pub underline: &'static str,
+    pub underline_off: &'static str,
+    pub inline_code_bg: &'static str,
+    pub default_fg: &'static str,
+}
+
+const COLOR_THEME: Theme = Theme {
+    reset: "\x1b[0m",
+    bold: "\x1b[1m",
+    dim: "\x1b[2m",
+    italic: "\x1b[3m",
+    heading: "\x1b[38;5;40m", // A vibrant green
+    blue: "\x1b[34m",
+    keyword: "\x1b[38;5;111m", // A distinct blue/cyan
+    yellow: "\x1b[33m",
+    red: "\x1b[31m",
+    magenta: "\x1b[35m",
+    green: "\x1b[32m",
+    cyan: "\x1b[36m",
+    quote: "\x1b[38;5;244m", // A muted grey
+    strikethrough: "\x1b[9m",
+    inverse: "\x1b[7m",
+    inverse_off: "\x1b[27m",
+    underline: "\x1b[4m",
+    underline_off: "\x1b[24m",
+    inline_code_bg: "\x1b[48;5;236m", // A subtle dark grey
+    default_fg: "\x1b[39m",
+};
+
+const PLAIN_THEME: Theme = Theme {
+    reset: "",
+    bold: "",
+    dim: "",
+    italic: "",
+    heading: "",
+    blue: "",
+    keyword: "",
+    yellow: "",
+    red: "",
+    magenta: "",
+    green: "",
+    cyan: "",
+    quote: "",
+    strikethrough: "",
+    inverse: "",
+    inverse_off: "",
+    underline: "",
+    underline_off: "",
+    inline_code_bg: "",
+    default_fg: "",
+};
+
+pub static COLOR_ENABLED: AtomicBool = AtomicBool::new(true);
+
+pub fn color_enabled() -> bool {
+    COLOR_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn theme() -> &'static Theme {
+    if color_enabled() {
+        &COLOR_THEME
+    } else {
+        &PLAIN_THEME
+    }
+}
+
+/// Decorative characters. The ASCII set stands in for terminals or locales
+/// that can't show the Unicode ones.
+pub struct Glyphs {
+    pub bullet: &'static str,
+    pub quote_bar: &'static str,
+    pub details: &'static str,
+    pub rule: &'static str,
+    pub box_top: &'static str,
+    pub box_bottom: &'static str,
+    /// Starts the continuation of a code line wrapped to fit the terminal.
+    pub continuation: &'static str,
+    /// Where a table's header rule meets its column separators.
+    pub cross: &'static str,
+    pub ellipsis: &'static str,
+    pub separator: &'static str,
+    pub note: &'static str,
+    pub tip: &'static str,
+    pub warning: &'static str,
+    pub spinner: &'static [&'static str],
+}
+
+const UNICODE_GLYPHS: Glyphs = Glyphs {
+    bullet: "▸",
+    quote_bar: "│",
+    details: "▶",
+    rule: "─",
+    box_top: "┌",
+    box_bottom: "└",
+    continuation: "↪",
+    cross: "┼",
+    ellipsis: "…",
+    separator: "·",
+    note: "ℹ",
+    tip: "✓",
+    warning: "⚠",
+    spinner: &["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"],
+};
+
+const ASCII_GLYPHS: Glyphs = Glyphs {
+    bullet: "*",
+    quote_bar: "|",
+    details: ">",
+    rule: "-",
+    box_top: "+",
+    box_bottom: "+",
+    continuation: ">",
+    cross: "+",
+    ellipsis: "...",
+    separator: "-",
+    note: "i",
+    tip: "+",
+    warning: "!",
+    spinner: &["|", "/", "-", "\\"],
+};
+
+pub static ASCII_ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn glyphs() -> &'static Glyphs {
+    if ASCII_ENABLED.load(Ordering::Relaxed) {
+        &ASCII_GLYPHS
+    } else {
+        &UNICODE_GLYPHS
+    }
+}
+
+/// LaTeX commands shown as Unicode when `math` is on.
+const MATH_SYMBOLS: &[(&str, &str)] = &[
+    ("alpha", "α"),
+    ("beta", "β"),
+    ("gamma", "γ"),
+    ("delta", "δ"),
+    ("epsilon", "ε"),
+    ("zeta", "ζ"),
+    ("eta", "η"),
+    ("theta", "θ"),
+    ("iota", "ι"),
+    ("kappa", "κ"),
+    ("lambda", "λ"),
+    ("mu", "μ"),
+    ("nu", "ν"),
+    ("xi", "ξ"),
+    ("pi", "π"),
+    ("rho", "ρ"),
+    ("sigma", "σ"),
+    ("tau", "τ"),
+    ("phi", "φ"),
+    ("chi", "χ"),
+    ("psi", "ψ"),
+    ("omega", "ω"),
+    ("Gamma", "Γ"),
+    ("Delta", "Δ"),
+    ("Theta", "Θ"),
+    ("Lambda", "Λ"),
+    ("Pi", "Π"),
+    ("Sigma", "Σ"),
+    ("Phi", "Φ"),
+    ("Omega", "Ω"),
+    ("times", "×"),
+    ("cdot", "·"),
+    ("div", "÷"),
+    ("pm", "±"),
+    ("leq", "≤"),
+    ("le", "≤"),
+    ("geq", "≥"),
+    ("ge", "≥"),
+    ("neq", "≠"),
+    ("ne", "≠"),
+    ("approx", "≈"),
+    ("equiv", "≡"),
+    ("infty", "∞"),
+    ("sum", "∑"),
+    ("prod", "∏"),
+    ("int", "∫"),
+    ("sqrt", "√"),
+    ("partial", "∂"),
+    ("nabla", "∇"),
+    ("in", "∈"),
+    ("notin", "∉"),
+    ("subset", "⊂"),
+    ("cup", "∪"),
+    ("cap", "∩"),
+    ("forall", "∀"),
+    ("exists", "∃"),
+    ("to", "→"),
+    ("rightarrow", "→"),
+    ("leftarrow", "←"),
+    ("Rightarrow", "⇒"),
+    ("ldots", "…"),
+    ("cdots", "⋯"),
+];
+const DEFAULT_TERMINAL_COLUMNS: usize = 80;
+const MIN_TERMINAL_COLUMNS: usize = 20;
+
+/// Prefix of a Mermaid Live Editor link; the diagram follows as base64 JSON.
+const MERMAID_LIVE_URL: &str = "https://mermaid.live/edit#base64:";
+
+/// How responses are displayed, set with `set render`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum RenderMode {
+    /// Fully rendered markdown.
+    Markdown,
+    /// The markdown source as written, with only code blocks highlighted.
+    Source,
+    /// The response text exactly as received.
+    Raw,
+}
+
+impl std::str::FromStr for RenderMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value {
+            "markdown" => Ok(RenderMode::Markdown),
+            "source" => Ok(RenderMode::Source),
+            "raw" => Ok(RenderMode::Raw),
+            _ => Err(format!(
+                "Expected 'markdown', 'source' or 'raw', got '{}'",
+                value
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for RenderMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RenderMode::Markdown => "markdown",
+            RenderMode::Source => "source",
+            RenderMode::Raw => "raw",
+        })
+    }
+}
+
+/// The parts of the REPL's settings that change how a response is rendered.
+#[derive(Clone)]
+pub struct RenderOptions {
+    /// How responses are displayed.
+    pub render: RenderMode,
+    /// Render inline code on a background color instead of wrapping it in backticks.
+    pub inline_bg: bool,
+    /// Dim struck-through text as well, for terminals without strikethrough.
+    pub strike_dim: bool,
+    /// bat theme for code blocks, or bat's default when unset.
+    pub theme: Option<String>,
+    /// Highlight math spans and show simple LaTeX as Unicode.
+    pub math: bool,
+    /// The whole response is numbered, so code blocks leave out bat's own numbers.
+    pub line_numbers: bool,
+    /// Pretty-print and highlight JSON that a response gives outside a code block.
+    pub autojson: bool,
+    /// Render responses that are JSON, CSV or TSV, or bare code, as such rather than as markdown.
+    pub autorender: bool,
+    /// Break words longer than the wrap width instead of letting them overflow.
+    pub break_long: bool,
+    /// Follow mermaid diagrams with a link that opens them in the Mermaid Live Editor.
+    pub mermaid_link: bool,
+    /// Show a response that is nothing but one code block without bat's grid
+    /// and line numbers, ready to copy.
+    pub bare_code: bool,
+    /// Drop blank lines at the start and end of a response.
+    pub trim: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            render: RenderMode::Markdown,
+            inline_bg: false,
+            strike_dim: true,
+            theme: None,
+            math: false,
+            line_numbers: false,
+            autojson: false,
+            autorender: false,
+            break_long: false,
+            mermaid_link: false,
+            bare_code: false,
+            trim: true,
+        }
+    }
+}
+
+/// Shortens `text` to at most `width` terminal columns for one-line previews,
+/// cutting at a word boundary when there is one and ending with an ellipsis.
+pub fn truncate_display(text: &str, width: usize) -> Cow<'_, str> {
+    if text.width() <= width {
+        return Cow::Borrowed(text);
+    }
+    if width == 0 {
+        return Cow::Borrowed("");
+    }
+    // Leave room for the ellipsis.
+    let ellipsis = glyphs().ellipsis;
+    let budget = width.saturating_sub(ellipsis.width());
+    let mut used = 0;
+    let mut end = 0;
+    let mut last_space = None;
+    for (i, grapheme) in text.grapheme_indices(true) {
+        let grapheme_width = grapheme.width();
+        if used + grapheme_width > budget {
+            break;
+        }
+        if grapheme.chars().all(char::is_whitespace) {
+            last_space = Some(i);
+        }
+        used += grapheme_width;
+        end = i + grapheme.len();
+    }
+    let at_boundary = text[end..].starts_with(char::is_whitespace);
+    let cut = match last_space {
+        Some(space) if !at_boundary && space > 0 => &text[..space],
+        _ => &text[..end],
+    };
+    Cow::Owned(format!("{}{}", cut.trim_end(), ellipsis))
+}
+
+/// Display width of `text`, measured per grapheme cluster so that emoji
+/// sequences (flags, skin tones, ZWJ families) and combining marks count as
+/// the one glyph a terminal draws for them.
+fn display_width(text: &str) -> usize {
+    text.graphemes(true).map(UnicodeWidthStr::width).sum()
+}
+
+/// Wraps `line` at spaces into lines of at most `width` columns. Words longer
+/// than that, like URLs or hashes, get a line of their own, or are broken
+/// between grapheme clusters with `break_long`. Spaces at a break are
+/// dropped, but those at the start and end of `line` are kept since they
+/// separate it from the inline code or emphasis around it.
+fn wrap_line(line: &str, width: usize, break_long: bool) -> Vec<&str> {
+    let width = width.max(1);
+    let mut lines = Vec::new();
+    let mut line_start = 0;
+    let mut column = 0;
+    let mut pos = 0;
+    let mut offset = 0;
+    for word in line.split(' ') {
+        let word_start = offset;
+        offset += word.len() + 1;
+        if word.is_empty() {
+            continue;
+        }
+        // The spaces before the word are ASCII, one column each.
+        let gap = word_start - pos;
+        let word_width = display_width(word);
+        if column > 0 && column + gap + word_width > width {
+            lines.push(&line[line_start..pos]);
+            line_start = word_start;
+            column = 0;
+        } else {
+            column += gap;
+        }
+        if column + word_width <= width || !break_long {
+            column += word_width;
+        } else {
+            for (i, grapheme) in word.grapheme_indices(true) {
+                let grapheme_width = grapheme.width();
+                if column > 0 && column + grapheme_width > width {
+                    lines.push(&line[line_start..word_start + i]);
+                    line_start = word_start + i;
+                    column = 0;
+                }
+                column += grapheme_width;
+            }
+        }
+        pos = word_start + word.len();
+    }
+    lines.push(&line[line_start..]);
+    lines
+}
+
+/// Computes the text wrap width for a terminal `cols` columns wide. Some
+/// terminals and multiplexers report zero or tiny widths, so anything below
+/// `MIN_TERMINAL_COLUMNS` is treated as the default width instead.
+fn wrap_width_for(cols: usize) -> usize {
+    let cols = if cols < MIN_TERMINAL_COLUMNS {
+        DEFAULT_TERMINAL_COLUMNS
+    } else {
+        cols
+    };
+    (cols * 3 / 4).min(100)
+}
+
+/// Renders markdown text to the terminal with ANSI colors and formatting.
+pub fn render_markdown(text: &str, options: &RenderOptions) -> String {
+    let mut out = String::new();
+    let text = if options.trim {
+        trim_blank_lines(text)
+    } else {
+        text
+    };
+    match options.render {
+        RenderMode::Markdown => {}
+        RenderMode::Source => {
+            render_source(text, options, &mut out);
+            return out;
+        }
+        RenderMode::Raw => {
+            out.push_str(text);
+            if !text.ends_with('\n') {
+                out.push('\n');
+            }
+            return out;
+        }
+    }
+    let t = theme();
+    let (cols, _rows) = term_size::dimensions().unwrap_or((DEFAULT_TERMINAL_COLUMNS, 24));
+    let wrap_width = wrap_width_for(cols);
+
+    let text = if options.autorender {
+        autorender(text)
+    } else {
+        Cow::Borrowed(text)
+    };
+    let text = match options.math.then(|| normalize_math_delimiters(&text)) {
+        Some(Cow::Owned(normalized)) => Cow::Owned(normalized),
+        _ => text,
+    };
+    let text = match fence_unfenced_diffs(&text) {
+        Cow::Owned(fenced) => Cow::Owned(fenced),
+        Cow::Borrowed(_) => text,
+    };
+    let text = match options.autojson.then(|| fence_json(&text)) {
+        Some(Cow::Owned(fenced)) => Cow::Owned(fenced),
+        _ => text,
+    };
+    // Collected up front so lists can be measured before their first item is printed.
+    // A full or collapsed reference to a label that is never defined becomes a
+    // link without a destination, shown as its text alone rather than as
+    // literal brackets. Shortcut ones stay text, since `[0]` or `[x]` in prose
+    // is far more likely than a link.
+    let broken_reference = |link: BrokenLink| match link.link_type {
+        LinkType::Reference | LinkType::Collapsed => Some((CowStr::from(""), CowStr::from(""))),
+        _ => None,
+    };
+    let events: Vec<Event> =
+        Parser::new_with_broken_link_callback(&text, ParserOptions::all(), Some(broken_reference))
+            .collect(); // Enable all markdown extensions
+    if let Some((language, code)) = options
+        .bare_code
+        .then(|| only_code_block(&events))
+        .flatten()
+    {
+        render_code_block(&code, language, options, true, &mut out);
+        return out;
+    }
+    let mut code_buffer = String::new();
+    let mut code_language = String::from("text");
+    let mut in_code_block = false;
+    let mut list_stack: Vec<(Option<u64>, usize)> = Vec::new(); // (next_num, number_width)
+    let mut link_stack: Vec<String> = Vec::new();
+    // Escape codes of the open headings, emphasis and links, outermost first,
+    // re-emitted after an inner one closes with a full reset.
+    let mut style_stack: Vec<String> = Vec::new();
+    let mut pending_newlines = 0;
+    let mut at_line_start = true;
+    let mut last_was_list_item = false;
+    // One bar color per open blockquote (`None` for an indented `<details>`
+    // body), and the prefix to print at each line start.
+    let mut quote_stack: Vec<Option<&'static str>> = Vec::new();
+    let mut quote_prefix = String::new();
+    // Open `<details>` blocks, and whether each has indented its body yet.
+    let mut details_stack: Vec<bool> = Vec::new();
+    let mut skip_events = 0;
+    // Footnote definitions are collected as plain text and printed at the end.
+    let mut footnote: Option<(String, String)> = None;
+    let mut footnotes: Vec<(String, String)> = Vec::new();
+
+    for (index, event) in events.iter().cloned().enumerate() {
+        if skip_events > 0 {
+            skip_events -= 1;
+            continue;
+        }
+        if let Some((_, note)) = &mut footnote {
+            match event {
+                Event::End(TagEnd::FootnoteDefinition) => footnotes.extend(footnote.take()),
+                Event::Text(text) | Event::Code(text) => note.push_str(&text),
+                Event::SoftBreak | Event::HardBreak => note.push(' '),
+                _ => {}
+            }
+            continue;
+        }
+        match event {
+            Event::Start(tag) => match tag {
+                Tag::Paragraph if !at_line_start && !last_was_list_item => {
+                    pending_newlines = pending_newlines.max(1);
+                }
+                Tag::Heading { level, .. } => {
+                    flush_newlines(&mut pending_newlines, 2, &mut out);
+                    let header_prefix = match level {
+                        HeadingLevel::H1 => "# ",
+                        HeadingLevel::H2 => "## ",
+                        HeadingLevel::H3 => "### ",
+                        HeadingLevel::H4 => "#### ",
+                        HeadingLevel::H5 => "##### ",
+                        HeadingLevel::H6 => "###### ",
+                    };
+                    style_stack.push(format!("{}{}", t.bold, t.heading));
+                    write!(out, "{}{}{}", t.bold, t.heading, header_prefix).unwrap();
+                    at_line_start = false;
+                }
+                Tag::BlockQuote(kind) => {
+                    flush_newlines(&mut pending_newlines, 1, &mut out);
+                    at_line_start = true;
+                    // GitHub-style `> [!NOTE]` quotes carry their kind; otherwise
+                    // look for a leading `**Note:**` label and drop it in favour of ours.
+                    let admonition = match kind {
+                        Some(kind) => Some(Admonition::from(kind)),
+                        None => admonition_label(&events[index + 1..]).inspect(|_| {
+                            skip_events = ADMONITION_LABEL_EVENTS;
+                        }),
+                    };
+                    let style = admonition.map(|admonition| admonition.style(t));
+                    quote_stack.push(Some(style.map_or(t.quote, |(_, color, _)| color)));
+                    quote_prefix = quote_bars(&quote_stack);
+                    if let Some((icon, color, label)) = style {
+                        write!(
+                            out,
+                            "{}{}{} {}:{}",
+                            quote_prefix, color, icon, label, t.default_fg
+                        )
+                        .unwrap();
+                        at_line_start = false;
+                        if kind.is_some() {
+                            pending_newlines = 1;
+                        }
+                    }
+                }
+                Tag::CodeBlock(kind) => {
+                    flush_newlines(&mut pending_newlines, 1, &mut out);
+                    in_code_block = true;
+                    code_language = match kind {
+                        CodeBlockKind::Fenced(lang) => {
+                            let lang_str = lang.to_string();
+                            if lang_str.is_empty() {
+                                "txt".to_string()
+                            } else {
+                                lang_str
+                            }
+                        }
+                        CodeBlockKind::Indented => "txt".to_string(),
+                    };
+                }
+                Tag::List(start_num) => {
+                    if !list_stack.is_empty() {
+                        pending_newlines = pending_newlines.max(1);
+                    } else {
+                        flush_newlines(&mut pending_newlines, 1, &mut out);
+                    }
+                    let width = start_num.map_or(0, |start| {
+                        ordered_number_width(start, list_item_count(&events, index))
+                    });
+                    list_stack.push((start_num, width));
+                }
+                Tag::Item => {
+                    if !at_line_start {
+                        writeln!(out).unwrap();
+                    }
+                    write!(out, "{}", quote_prefix).unwrap();
+
+                    let current_level = list_stack.len().saturating_sub(1);
+                    let indent = "  ".repeat(current_level);
+
+                    if let Some((Some(num), width)) = list_stack.last_mut() {
+                        write!(
+                            out,
+                            "{}{}{:>width$}. {}",
+                            indent,
+                            t.magenta,
+                            num,
+                            t.reset,
+                            width = *width
+                        )
+                        .unwrap();
+                        *num += 1;
+                    } else {
+                        write!(
+                            out,
+                            "{}{} {} {}",
+                            indent,
+                            t.magenta,
+                            glyphs().bullet,
+                            t.reset
+                        )
+                        .unwrap();
+                    }
+                    at_line_start = false;
+                    last_was_list_item = true;
+                    pending_newlines = 0;
+                }
+                Tag::Emphasis => {
+                    style_stack.push(t.italic.to_string());
+                    write!(out, "{}", t.italic).unwrap();
+                }
+                Tag::Strong => {
+                    style_stack.push(format!("{}{}", t.bold, t.yellow));
+                    write!(out, "{}{}", t.bold, t.yellow).unwrap();
+                }
+                Tag::Strikethrough => {
+                    // Many terminals ignore the strikethrough attribute, so
+                    // dim the text too to keep it visibly de-emphasized.
+                    let style = if options.strike_dim {
+                        format!("{}{}", t.strikethrough, t.dim)
+                    } else {
+                        t.strikethrough.to_string()
+                    };
+                    write!(out, "{}", style).unwrap();
+                    style_stack.push(style);
+                }
+                Tag::Link { dest_url, .. } => {
+                    if dest_url.is_empty() {
+                        style_stack.push(String::new());
+                    } else {
+                        style_stack.push(t.blue.to_string());
+                        write!(out, "{}[", t.blue).unwrap();
+                    }
+                    link_stack.push(dest_url.to_string());
+                }
+                Tag::Table(alignments) => {
+                    let (rows, events_used) = table_rows(&events[index + 1..]);
+                    skip_events = events_used;
+                    // A blank line sets the table apart, unless it opens the response.
+                    let blank_lines = if out.is_empty() { 0 } else { 2 };
+                    flush_newlines(&mut pending_newlines, blank_lines, &mut out);
+                    render_table(&rows, &alignments, &mut out);
+                    pending_newlines = pending_newlines.max(1);
+                    at_line_start = true;
+                }
+                Tag::FootnoteDefinition(label) => {
+                    footnote = Some((label.to_string(), String::new()));
+                }
+                _ => {}
+            },
+            Event::End(tag) => match tag {
+                TagEnd::Heading(_) => {
+                    style_stack.pop();
+                    write!(out, "{}", t.reset).unwrap();
+                    pending_newlines = pending_newlines.max(2);
+                    at_line_start = true;
+                }
+                TagEnd::BlockQuote => {
+                    quote_stack.pop();
+                    quote_prefix = quote_bars(&quote_stack);
+                    pending_newlines = pending_newlines.max(1);
+                    at_line_start = true;
+                }
+                TagEnd::CodeBlock => {
+                    in_code_block = false;
+                    render_code_block(&code_buffer, &code_language, options, false, &mut out);
+                    code_buffer.clear();
+                    code_language = String::from("text");
+                    pending_newlines = pending_newlines.max(1);
+                    at_line_start = true;
+                }
+                TagEnd::List(_) => {
+                    list_stack.pop();
+                    if list_stack.is_empty() {
+                        pending_newlines = pending_newlines.max(1);
+                        last_was_list_item = false;
+                    }
+                    at_line_start = true;
+                }
+                TagEnd::Item => {
+                    // Don't add extra newlines here, handled by next item or list end
+                }
+                TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough => {
+                    style_stack.pop();
+                    restore_styles(&style_stack, &mut out);
+                }
+                TagEnd::Link => {
+                    style_stack.pop();
+                    match link_stack.pop() {
+                        // Nowhere to point to, so just the text.
+                        Some(url) if url.is_empty() => {}
+                        Some(url) => {
+                            write!(out, "]({}{}", t.blue, url).unwrap();
+                            restore_styles(&style_stack, &mut out);
+                            write!(out, ")").unwrap();
+                        }
+                        None => write!(out, "]").unwrap(),
+                    }
+                }
+                _ => {}
+            },
+            Event::Text(text) => {
+                if in_code_block {
+                    code_buffer.push_str(&text);
+                } else {
+                    if pending_newlines > 0 {
+                        at_line_start = true;
+                    }
+                    flush_newlines(&mut pending_newlines, 0, &mut out);
+                    render_text(
+                        &text,
+                        wrap_width,
+                        options.break_long,
+                        &list_stack,
+                        &quote_prefix,
+                        &mut at_line_start,
+                        &mut out,
+                    );
+                }
+            }
+            Event::Code(text) => {
+                if options.inline_bg {
+                    write!(out, "{}{} {} ", t.inline_code_bg, t.keyword, text).unwrap();
+                } else {
+                    write!(out, "{}`{}`", t.keyword, text).unwrap();
+                }
+                // Back to the surrounding style, so bold around a code span survives.
+                restore_styles(&style_stack, &mut out);
+                at_line_start = false;
+            }
+            Event::InlineMath(math) => {
+                flush_newlines(&mut pending_newlines, 0, &mut out);
+                if options.math {
+                    write!(out, "{}{}{}", t.cyan, t.italic, math_to_unicode(&math)).unwrap();
+                    restore_styles(&style_stack, &mut out);
+                } else {
+                    write!(out, "${}$", math).unwrap();
+                }
+                at_line_start = false;
+            }
+            Event::DisplayMath(math) => {
+                if options.math {
+                    flush_newlines(&mut pending_newlines, 0, &mut out);
+                    if !at_line_start {
+                        writeln!(out).unwrap();
+                    }
+                    for line in math_to_unicode(math.trim()).lines() {
+                        writeln!(out, "    {}{}{}{}", t.cyan, t.italic, line.trim(), t.reset)
+                            .unwrap();
+                    }
+                    at_line_start = true;
+                } else {
+                    flush_newlines(&mut pending_newlines, 0, &mut out);
+                    write!(out, "$${}$$", math).unwrap();
+                    at_line_start = false;
+                }
+            }
+            Event::FootnoteReference(label) => {
+                flush_newlines(&mut pending_newlines, 0, &mut out);
+                write!(out, "{}[^{}]", t.blue, label).unwrap();
+                restore_styles(&style_stack, &mut out);
+                at_line_start = false;
+            }
+            Event::HardBreak => {
+                writeln!(out).unwrap();
+                at_line_start = true;
+            }
+            Event::SoftBreak if !at_line_start => {
+                write!(out, " ").unwrap();
+            }
+            Event::Rule => {
+                flush_newlines(&mut pending_newlines, 1, &mut out);
+                writeln!(
+                    out,
+                    "{}{}{}",
+                    t.dim,
+                    glyphs().rule.repeat(wrap_width.min(50)),
+                    t.reset
+                )
+                .unwrap();
+                pending_newlines = pending_newlines.max(1);
+                at_line_start = true;
+            }
+            // Tags are stripped; `<details>` gets a summary header and an
+            // indented body, since a terminal can't collapse it, and `<kbd>`
+            // keys are shown inverted like keycaps.
+            Event::Html(html) | Event::InlineHtml(html) => {
+                for piece in html_pieces(&html) {
+                    match piece {
+                        HtmlPiece::DetailsStart => details_stack.push(false),
+                        HtmlPiece::SummaryStart => {
+                            flush_newlines(&mut pending_newlines, 1, &mut out);
+                            if !at_line_start {
+                                writeln!(out).unwrap();
+                            }
+                            write!(out, "{}{}{} ", quote_prefix, t.bold, glyphs().details).unwrap();
+                            at_line_start = false;
+                        }
+                        HtmlPiece::SummaryEnd => {
+                            write!(out, "{}", t.reset).unwrap();
+                            if let Some(indented @ false) = details_stack.last_mut() {
+                                *indented = true;
+                                quote_stack.push(None);
+                                quote_prefix = quote_bars(&quote_stack);
+                            }
+                            pending_newlines = pending_newlines.max(1);
+                            at_line_start = true;
+                        }
+                        HtmlPiece::DetailsEnd => {
+                            if details_stack.pop() == Some(true) {
+                                quote_stack.pop();
+                                quote_prefix = quote_bars(&quote_stack);
+                            }
+                            pending_newlines = pending_newlines.max(1);
+                            at_line_start = true;
+                        }
+                        HtmlPiece::KbdStart => {
+                            flush_newlines(&mut pending_newlines, 0, &mut out);
+                            write!(out, "{}{}", t.inverse, keycap_padding()).unwrap();
+                            at_line_start = false;
+                        }
+                        HtmlPiece::KbdEnd => {
+                            write!(out, "{}{}", keycap_padding(), t.inverse_off).unwrap();
+                        }
+                        HtmlPiece::Text(text) if !text.trim().is_empty() => {
+                            if pending_newlines > 0 {
+                                at_line_start = true;
+                            }
+                            flush_newlines(&mut pending_newlines, 0, &mut out);
+                            render_text(
+                                text.trim(),
+                                wrap_width,
+                                options.break_long,
+                                &list_stack,
+                                &quote_prefix,
+                                &mut at_line_start,
+                                &mut out,
+                            );
+                        }
+                        HtmlPiece::Text(_) => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if !footnotes.is_empty() {
+        if !at_line_start {
+            writeln!(out).unwrap();
+        }
+        writeln!(out).unwrap();
+        for (label, note) in &footnotes {
+            let note = format!("[^{}] {}", label, note.trim());
+            for line in wrap_line(&note, wrap_width.saturating_sub(2), options.break_long) {
+                writeln!(out, "  {}{}{}", t.dim, line, t.reset).unwrap();
+            }
+        }
+        at_line_start = true;
+    }
+
+    // Final cleanup
+    if !at_line_start {
+        writeln!(out).unwrap();
+    }
+    out
+}
+
+/// Wraps diffs that aren't in a code fence, starting at a `diff --git`,
+/// `--- `/`+++ ` or `@@ -` line, in a ```` ```diff ```` fence so they aren't
+/// reflowed as prose. A diff ends at a blank line, at the first line that
+/// can't be part of one, or once the lines counted by its last `@@` hunk
+/// header are used up and what follows isn't another header.
+fn fence_unfenced_diffs(text: &str) -> Cow<'_, str> {
+    if !text.contains("diff --git") && !text.contains("@@ -") {
+        return Cow::Borrowed(text);
+    }
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let mut out = String::with_capacity(text.len() + 16);
+    let mut in_fence = false;
+    let mut in_diff = false;
+    let mut hunk = None;
+    for (i, line) in lines.iter().enumerate() {
+        let trimmed = line.trim_start();
+        if !in_diff && (trimmed.starts_with("```") || trimmed.starts_with("~~~")) {
+            in_fence = !in_fence;
+        }
+        let starts_diff = line.starts_with("diff --git ")
+            || line.starts_with("@@ -")
+            || (line.starts_with("--- ")
+                && lines
+                    .get(i + 1)
+                    .is_some_and(|next| next.starts_with("+++ ")));
+        if !in_fence && !in_diff && starts_diff {
+            out.push_str("```diff\n");
+            in_diff = true;
+            hunk = hunk_counts(line);
+        } else if in_diff && !continues_diff(line, &mut hunk) {
+            out.push_str("```\n");
+            in_diff = false;
+        }
+        out.push_str(line);
+        if in_diff && !line.ends_with('\n') {
+            out.push('\n');
+        }
+    }
+    if in_diff {
+        out.push_str("```\n");
+    }
+    Cow::Owned(out)
+}
+
+/// Pretty-prints the whole of `text`, or any paragraph of it, that is a JSON
+/// object or array and puts it in a ```` ```json ```` fence. Anything that
+/// doesn't parse completely, or is an empty object or array, is left alone.
+fn fence_json(text: &str) -> Cow<'_, str> {
+    if let Some(json) = pretty_json(text) {
+        return Cow::Owned(format!("```json\n{}\n```\n", json));
+    }
+    if !text.contains('{') && !text.contains('[') {
+        return Cow::Borrowed(text);
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut paragraph = String::new();
+    let mut in_fence = false;
+    let mut changed = false;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        }
+        paragraph.push_str(line);
+        if trimmed.is_empty() && !in_fence {
+            changed |= push_paragraph(&mut out, &paragraph);
+            paragraph.clear();
+        }
+    }
+    changed |= push_paragraph(&mut out, &paragraph);
+    if changed {
+        Cow::Owned(out)
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Adds `paragraph` to `out`, fenced and pretty-printed if it is JSON.
+/// Returns whether it was.
+fn push_paragraph(out: &mut String, paragraph: &str) -> bool {
+    match pretty_json(paragraph) {
+        Some(json) => {
+            let trailing = &paragraph[paragraph.trim_end().len()..];
+            let blank_lines = trailing.split_once('\n').map_or("", |(_, rest)| rest);
+            write!(out, "```json\n{}\n```\n{}", json, blank_lines).unwrap();
+            true
+        }
+        None => {
+            out.push_str(paragraph);
+            false
+        }
+    }
+}
+
+/// `text` pretty-printed, if it is a non-empty JSON object or array.
+fn pretty_json(text: &str) -> Option<String> {
+    let text = text.trim();
+    if !text.starts_with(['{', '[']) {
+        return None;
+    }
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+    let non_empty = match &value {
+        serde_json::Value::Object(map) => !map.is_empty(),
+        serde_json::Value::Array(items) => !items.is_empty(),
+        _ => false,
+    };
+    non_empty.then(|| serde_json::to_string_pretty(&value).ok())?
+}
+
+/// What a response turns out to be, for `autorender`.
+#[derive(Debug, PartialEq)]
+enum ContentKind {
+    Markdown,
+    /// A JSON object or array, pretty-printed.
+    Json(String),
+    /// Comma- or tab-separated values, split into rows of fields.
+    Table(Vec<Vec<String>>),
+    /// Source code without a fence, in the language its first line gives away.
+    Code(String),
+}
+
+fn detect_content(text: &str) -> ContentKind {
+    if let Some(json) = pretty_json(text) {
+        return ContentKind::Json(json);
+    }
+    if let Some(rows) = delimited_rows(text) {
+        return ContentKind::Table(rows);
+    }
+    if let Some(language) = code_language(text) {
+        return ContentKind::Code(language);
+    }
+    ContentKind::Markdown
+}
+
+/// Routes a response to the renderer that suits what it contains: JSON to
+/// the highlighted code block, CSV and TSV to the table renderer, and code
+/// with a telltale first line to its language's highlighting. Each is
+/// rewritten as the markdown for that renderer; markdown passes through.
+fn autorender(text: &str) -> Cow<'_, str> {
+    match detect_content(text) {
+        ContentKind::Markdown => Cow::Borrowed(text),
+        ContentKind::Json(json) => Cow::Owned(format!("```json\n{}\n```\n", json)),
+        ContentKind::Table(rows) => Cow::Owned(markdown_table(&rows)),
+        ContentKind::Code(language) => Cow::Owned(format!(
+            "```{}\n{}\n```\n",
+            language,
+            text.trim_matches('\n')
+        )),
+    }
+}
+
+/// The rows of `text` if it is a table of comma- or tab-separated values:
+/// at least two lines, all with the same number of fields, and more than one.
+fn delimited_rows(text: &str) -> Option<Vec<Vec<String>>> {
+    let lines: Vec<&str> = text.trim().lines().collect();
+    if lines.len() < 2 || lines[0].starts_with(['#', '-', '*', '>', '|']) {
+        return None;
+    }
+    // Sentences with the same number of commas would otherwise pass for rows.
+    if lines
+        .iter()
+        .any(|line| line.trim_end().ends_with(['.', '!', '?', ':']))
+    {
+        return None;
+    }
+    let separator = if lines[0].contains('\t') { '\t' } else { ',' };
+    let rows: Vec<Vec<String>> = lines
+        .iter()
+        .map(|line| split_fields(line.trim_end_matches('\r'), separator))
+        .collect();
+    let columns = rows[0].len();
+    (columns > 1 && rows.iter().all(|row| row.len() == columns)).then_some(rows)
+}
+
+/// Splits a line of delimited text into its fields. Quoted fields may contain
+/// the separator, and `""` inside them stands for a quote.
+fn split_fields(line: &str, separator: char) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            c if c == separator && !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// A markdown table of `rows`, the first of which is the header.
+fn markdown_table(rows: &[Vec<String>]) -> String {
+    let mut out = String::new();
+    for (i, row) in rows.iter().enumerate() {
+        out.push('|');
+        for field in row {
+            write!(out, " {} |", field.trim().replace('|', "\\|")).unwrap();
+        }
+        out.push('\n');
+        if i == 0 {
+            writeln!(out, "|{}", " --- |".repeat(row.len())).unwrap();
+        }
+    }
+    out
+}
+
+/// The language of unfenced source code, recognized from its first line the
+/// way editors do: a shebang, `<?xml`, `<!DOCTYPE html>` and the like.
+fn code_language(text: &str) -> Option<String> {
+    if text.contains("```") {
+        return None;
+    }
+    let first_line = text.trim_start().lines().next()?;
+    let assets = HighlightingAssets::from_binary();
+    let syntax = assets
+        .get_syntax_set()
+        .ok()?
+        .find_syntax_by_first_line(first_line)?;
+    Some(
+        syntax
+            .file_extensions
+            .first()
+            .unwrap_or(&syntax.name)
+            .clone(),
+    )
+}
+
+/// The old and new line counts of an `@@ -a,b +c,d @@` hunk header, where an
+/// omitted count means one line.
+fn hunk_counts(line: &str) -> Option<(usize, usize)> {
+    let mut ranges = line.strip_prefix("@@ -")?.split_whitespace();
+    let count = |range: &str| match range.split_once(',') {
+        Some((_, count)) => count.parse().ok(),
+        None => range.parse::<usize>().ok().map(|_| 1),
+    };
+    let old = count(ranges.next()?)?;
+    let new = count(ranges.next()?.strip_prefix('+')?)?;
+    Some((old, new))
+}
+
+/// Whether `line` continues a diff, given the lines left in the current hunk.
+/// Without a parsable hunk header any diff-looking line continues it; once the
+/// hunk's lines are used up only headers do.
+fn continues_diff(line: &str, hunk: &mut Option<(usize, usize)>) -> bool {
+    if line.starts_with("@@ ") {
+        *hunk = hunk_counts(line);
+        return true;
+    }
+    match hunk {
+        Some((old, new)) if *old > 0 || *new > 0 => {
+            match line.as_bytes().first() {
+                Some(b' ') => {
+                    *old = old.saturating_sub(1);
+                    *new = new.saturating_sub(1);
+                }
+                Some(b'-') => *old = old.saturating_sub(1),
+                Some(b'+') => *new = new.saturating_sub(1),
+                Some(b'\\') => {}
+                _ => return false,
+            }
+            true
+        }
+        Some(_) => {
+            line.starts_with('\\')
+                || line.starts_with("--- ")
+                || line.starts_with("+++ ")
+                || is_diff_header(line)
+        }
+        None => is_diff_line(line),
+    }
+}
+
+/// Whether `line` can appear in the body or headers of a unified diff.
+fn is_diff_line(line: &str) -> bool {
+    line.starts_with([' ', '+', '-', '@', '\\']) || is_diff_header(line)
+}
+
+/// Whether `line` is a git diff header line such as `diff --git` or `index`.
+fn is_diff_header(line: &str) -> bool {
+    const HEADERS: &[&str] = &[
+        "diff ",
+        "index ",
+        "new file",
+        "deleted file",
+        "old mode",
+        "new mode",
+        "similarity index",
+        "rename ",
+        "Binary files",
+    ];
+    HEADERS.iter().any(|header| line.starts_with(header))
+}
+
+/// Prints a diff with additions in green, deletions in red and hunk headers
+/// in cyan, instead of leaving the colors to the bat theme.
+fn render_diff(code: &str, out: &mut String) {
+    let t = theme();
+    for line in code.lines() {
+        let color =
+            if line.starts_with("+++") || line.starts_with("---") || line.starts_with("diff ") {
+                t.bold
+            } else if line.starts_with('+') {
+                t.green
+            } else if line.starts_with('-') {
+                t.red
+            } else if line.starts_with("@@") {
+                t.cyan
+            } else {
+                ""
+            };
+        if color.is_empty() {
+            writeln!(out, "{}", line).unwrap();
+        } else {
+            writeln!(out, "{}{}{}", color, line, t.reset).unwrap();
+        }
+    }
+}
+
+/// Rewrites `\(...\)` and `\[...\]` math delimiters to the `$...$` and
+/// `$$...$$` forms the markdown parser understands, leaving code alone.
+fn normalize_math_delimiters(text: &str) -> Cow<'_, str> {
+    if !text.contains("\\(") && !text.contains("\\[") {
+        return Cow::Borrowed(text);
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut in_fence = false;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        }
+        if in_fence {
+            out.push_str(line);
+            continue;
+        }
+        let mut in_code = false;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '`' {
+                in_code = !in_code;
+            }
+            let delimiter = match chars.peek() {
+                Some('(' | ')') if c == '\\' && !in_code => "$",
+                Some('[' | ']') if c == '\\' && !in_code => "$$",
+                _ => {
+                    out.push(c);
+                    continue;
+                }
+            };
+            chars.next();
+            out.push_str(delimiter);
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// Replaces common LaTeX commands and simple super- and subscripts with
+/// Unicode. Anything it can't convert is kept as written.
+fn math_to_unicode(math: &str) -> String {
+    let mut out = String::with_capacity(math.len());
+    let mut chars = math.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let mut name = String::new();
+                while let Some(&next) = chars.peek().filter(|next| next.is_ascii_alphabetic()) {
+                    name.push(next);
+                    chars.next();
+                }
+                match MATH_SYMBOLS.iter().find(|(command, _)| *command == name) {
+                    Some((_, symbol)) => out.push_str(symbol),
+                    None => {
+                        out.push('\\');
+                        out.push_str(&name);
+                    }
+                }
+            }
+            '^' | '_' => {
+                let (script, written) = if chars.peek() == Some(&'{') {
+                    chars.next();
+                    let script: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                    let written = format!("{}{{{}}}", c, script);
+                    (script, written)
+                } else {
+                    let script: String = chars.next().into_iter().collect();
+                    let written = format!("{}{}", c, script);
+                    (script, written)
+                };
+                let converted: Option<String> = script
+                    .chars()
+                    .map(|s| {
+                        if c == '^' {
+                            superscript(s)
+                        } else {
+                            subscript(s)
+                        }
+                    })
+                    .collect();
+                match converted {
+                    Some(converted) if !converted.is_empty() => out.push_str(&converted),
+                    _ => out.push_str(&written),
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn superscript(c: char) -> Option<char> {
+    Some(match c {
+        '0' => '⁰',
+        '1' => '¹',
+        '2' => '²',
+        '3' => '³',
+        '4' => '⁴',
+        '5' => '⁵',
+        '6' => '⁶',
+        '7' => '⁷',
+        '8' => '⁸',
+        '9' => '⁹',
+        '+' => '⁺',
+        '-' => '⁻',
+        '=' => '⁼',
+        '(' => '⁽',
+        ')' => '⁾',
+        'n' => 'ⁿ',
+        'i' => 'ⁱ',
+        _ => return None,
+    })
+}
+
+fn subscript(c: char) -> Option<char> {
+    Some(match c {
+        '0' => '₀',
+        '1' => '₁',
+        '2' => '₂',
+        '3' => '₃',
+        '4' => '₄',
+        '5' => '₅',
+        '6' => '₆',
+        '7' => '₇',
+        '8' => '₈',
+        '9' => '₉',
+        '+' => '₊',
+        '-' => '₋',
+        '=' => '₌',
+        '(' => '₍',
+        ')' => '₎',
+        'a' => 'ₐ',
+        'e' => 'ₑ',
+        'i' => 'ᵢ',
+        'j' => 'ⱼ',
+        'k' => 'ₖ',
+        'm' => 'ₘ',
+        'n' => 'ₙ',
+        'o' => 'ₒ',
+        't' => 'ₜ',
+        'x' => 'ₓ',
+        _ => return None,
+    })
+}
+
+/// Counts the items of the list that starts at `events[start]`, not
+/// including those of nested lists.
+fn list_item_count(events: &[Event], start: usize) -> usize {
+    let mut depth = 0;
+    let mut count = 0;
+    for event in &events[start..] {
+        match event {
+            Event::Start(Tag::List(_)) => depth += 1,
+            Event::End(TagEnd::List(_)) => {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            Event::Start(Tag::Item) if depth == 1 => count += 1,
+            _ => {}
+        }
+    }
+    count
+}
+
+/// Returns the width that fits every number of an ordered list starting at
+/// `start`, so `98.` to `100.` line up. At least two columns, so short lists
+/// keep their usual indent.
+fn ordered_number_width(start: u64, items: usize) -> usize {
+    let last = start.saturating_add((items as u64).saturating_sub(1));
+    last.to_string().len().max(2)
+}
+
+/// How many events make up a `**Note:**` admonition label: the paragraph
+/// start, the strong start, its text and the strong end.
+const ADMONITION_LABEL_EVENTS: usize = 4;
+
+/// A callout blockquote, from `> [!NOTE]` or a leading `**Note:**`.
+#[derive(Clone, Copy)]
+enum Admonition {
+    Note,
+    Tip,
+    Important,
+    Warning,
+    Caution,
+}
+
+impl Admonition {
+    /// Returns the icon, color and label for this kind of callout.
+    fn style(self, t: &Theme) -> (&'static str, &'static str, &'static str) {
+        let g = glyphs();
+        match self {
+            Admonition::Note => (g.note, t.blue, "Note"),
+            Admonition::Tip => (g.tip, t.green, "Tip"),
+            Admonition::Important => ("!", t.magenta, "Important"),
+            Admonition::Warning => (g.warning, t.yellow, "Warning"),
+            Admonition::Caution => (g.warning, t.red, "Caution"),
+        }
+    }
+}
+
+impl From<BlockQuoteKind> for Admonition {
+    fn from(kind: BlockQuoteKind) -> Self {
+        match kind {
+            BlockQuoteKind::Note => Admonition::Note,
+            BlockQuoteKind::Tip => Admonition::Tip,
+            BlockQuoteKind::Important => Admonition::Important,
+            BlockQuoteKind::Warning => Admonition::Warning,
+            BlockQuoteKind::Caution => Admonition::Caution,
+        }
+    }
+}
+
+/// Recognizes a blockquote whose first paragraph opens with a bold label
+/// like `**Note:**`, given the events following the blockquote start.
+fn admonition_label(events: &[Event]) -> Option<Admonition> {
+    let [Event::Start(Tag::Paragraph), Event::Start(Tag::Strong), Event::Text(label), Event::End(TagEnd::Strong), ..] =
+        events
+    else {
+        return None;
+    };
+    let label = label.trim().trim_end_matches(':').to_lowercase();
+    Some(match label.as_str() {
+        "note" | "info" => Admonition::Note,
+        "tip" | "hint" => Admonition::Tip,
+        "important" => Admonition::Important,
+        "warning" => Admonition::Warning,
+        "caution" | "danger" => Admonition::Caution,
+        _ => return None,
+    })
+}
+
+/// Collects the cells of a table as plain text, from the events after its
+/// `Start(Table)`, and returns them with the number of events up to and
+/// including its end.
+fn table_rows(events: &[Event]) -> (Vec<Vec<String>>, usize) {
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    for (i, event) in events.iter().enumerate() {
+        match event {
+            Event::Start(Tag::TableHead | Tag::TableRow) => rows.push(Vec::new()),
+            Event::Start(Tag::TableCell) => rows.last_mut().unwrap().push(String::new()),
+            Event::Text(text) | Event::Code(text) | Event::InlineMath(text) => {
+                if let Some(cell) = rows.last_mut().and_then(|row| row.last_mut()) {
+                    cell.push_str(text);
+                }
+            }
+            Event::SoftBreak | Event::HardBreak => {
+                if let Some(cell) = rows.last_mut().and_then(|row| row.last_mut()) {
+                    cell.push(' ');
+                }
+            }
+            Event::End(TagEnd::Table) => return (rows, i + 1),
+            _ => {}
+        }
+    }
+    (rows, events.len())
+}
+
+/// Splits the space left over in a cell into padding before and after its
+/// text, following the column's alignment from the delimiter row.
+fn align_padding(alignment: Alignment, spare: usize) -> (usize, usize) {
+    match alignment {
+        Alignment::None | Alignment::Left => (0, spare),
+        Alignment::Center => (spare / 2, spare - spare / 2),
+        Alignment::Right => (spare, 0),
+    }
+}
+
+/// Draws a table with its columns padded to a common width and aligned as
+/// the delimiter row says (`:---`, `:---:`, `---:`), the first row in bold
+/// as the header and ruled off from the rest.
+fn render_table(rows: &[Vec<String>], alignments: &[Alignment], out: &mut String) {
+    let t = theme();
+    let g = glyphs();
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| {
+            rows.iter()
+                .filter_map(|row| row.get(column))
+                .map(|cell| display_width(cell.trim()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    for (i, row) in rows.iter().enumerate() {
+        for (column, width) in widths.iter().enumerate() {
+            if column > 0 {
+                write!(out, "{}{}{}", t.dim, g.quote_bar, t.reset).unwrap();
+            }
+            let cell = row.get(column).map_or("", |cell| cell.trim());
+            let alignment = alignments.get(column).copied().unwrap_or(Alignment::None);
+            let (before, after) = align_padding(alignment, width - display_width(cell));
+            let (before, after) = (" ".repeat(before + 1), " ".repeat(after + 1));
+            if i == 0 {
+                write!(out, "{}{}{}{}{}", before, t.bold, cell, t.reset, after).unwrap();
+            } else {
+                write!(out, "{}{}{}", before, cell, after).unwrap();
+            }
+        }
+        writeln!(out).unwrap();
+        if i == 0 {
+            let rules: Vec<String> = widths
+                .iter()
+                .map(|width| g.rule.repeat(width + 2))
+                .collect();
+            writeln!(out, "{}{}{}", t.dim, rules.join(g.cross), t.reset).unwrap();
+        }
+    }
+}
+
+/// Builds the line prefix for the open blockquotes, one colored bar each,
+/// with plain indentation for `<details>` bodies.
+fn quote_bars(colors: &[Option<&str>]) -> String {
+    let t = theme();
+    colors
+        .iter()
+        .map(|color| match color {
+            Some(color) => format!("{}{}{} ", color, glyphs().quote_bar, t.default_fg),
+            None => "  ".to_string(),
+        })
+        .collect()
+}
+
+/// The parts of raw HTML the renderer cares about; other tags are dropped.
+#[derive(Debug, PartialEq)]
+enum HtmlPiece<'a> {
+    DetailsStart,
+    DetailsEnd,
+    SummaryStart,
+    SummaryEnd,
+    KbdStart,
+    KbdEnd,
+    Text(&'a str),
+}
+
+/// Space on either side of a `<kbd>` key, so the inverted block reads as a
+/// key. Without colors there is no block, and `Ctrl+C` reads best as is.
+fn keycap_padding() -> &'static str {
+    if color_enabled() {
+        " "
+    } else {
+        ""
+    }
+}
+
+/// Splits raw HTML into `<details>`/`<summary>`/`<kbd>` tags and the text
+/// between tags.
+fn html_pieces(html: &str) -> Vec<HtmlPiece<'_>> {
+    let mut pieces = Vec::new();
+    let mut rest = html;
+    while let Some(open) = rest.find('<') {
+        if open > 0 {
+            pieces.push(HtmlPiece::Text(&rest[..open]));
+        }
+        let Some(close) = rest[open..].find('>') else {
+            rest = &rest[open..];
+            break;
+        };
+        let tag = &rest[open + 1..open + close];
+        let (closing, tag) = match tag.strip_prefix('/') {
+            Some(tag) => (true, tag),
+            None => (false, tag),
+        };
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        match (name.as_str(), closing) {
+            ("details", false) => pieces.push(HtmlPiece::DetailsStart),
+            ("details", true) => pieces.push(HtmlPiece::DetailsEnd),
+            ("summary", false) => pieces.push(HtmlPiece::SummaryStart),
+            ("summary", true) => pieces.push(HtmlPiece::SummaryEnd),
+            ("kbd", false) => pieces.push(HtmlPiece::KbdStart),
+            ("kbd", true) => pieces.push(HtmlPiece::KbdEnd),
+            _ => {}
+        }
+        rest = &rest[open + close + 1..];
+    }
+    if !rest.is_empty() {
+        pieces.push(HtmlPiece::Text(rest));
+    }
+    pieces
+}
+
+/// Prints markdown as written, except that fenced code blocks are
+/// highlighted like in fully rendered output.
+fn render_source(text: &str, options: &RenderOptions, out: &mut String) {
+    let mut fence: Option<(&str, &str)> = None; // (marker, language)
+    let mut code = String::new();
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        match fence {
+            Some((marker, language)) if trimmed.starts_with(marker) => {
+                render_code_block(&code, language, options, false, out);
+                code.clear();
+                fence = None;
+                writeln!(out, "{}", line).unwrap();
+            }
+            Some(_) => {
+                code.push_str(line);
+                code.push('\n');
+            }
+            None => {
+                if let Some(marker) = ["```", "~~~"].into_iter().find(|m| trimmed.starts_with(m)) {
+                    let language = trimmed.trim_start_matches(marker).trim();
+                    fence = Some((marker, if language.is_empty() { "txt" } else { language }));
+                }
+                writeln!(out, "{}", line).unwrap();
+            }
+        }
+    }
+    // An unclosed fence, e.g. a truncated response, still gets highlighted.
+    if let Some((_, language)) = fence {
+        render_code_block(&code, language, options, false, out);
+    }
+}
+
+/// Ends an inline style with a full reset, then re-enters the ones still open
+/// around it.
+fn restore_styles(style_stack: &[String], out: &mut String) {
+    out.push_str(theme().reset);
+    for style in style_stack {
+        out.push_str(style);
+    }
+}
+
+fn flush_newlines(pending: &mut usize, min_newlines: usize, out: &mut String) {
+    let newlines_to_print = (*pending).max(min_newlines);
+    for _ in 0..newlines_to_print {
+        writeln!(out).unwrap();
+    }
+    *pending = 0;
+}
+
+fn render_text(
+    text: &str,
+    wrap_width: usize,
+    break_long: bool,
+    list_stack: &[(Option<u64>, usize)],
+    quote_prefix: &str,
+    at_line_start: &mut bool,
+    out: &mut String,
+) {
+    let current_indent = if !list_stack.is_empty() {
+        let indent_level = list_stack.len() - 1;
+        "  ".repeat(indent_level + 1) // +1 for alignment with list marker
+    } else {
+        String::new()
+    };
+
+    let text = text.trim_start_matches('\n').trim_end_matches('\n');
+    if text.is_empty() {
+        return;
+    }
+
+    // Handle blockquote prefix
+    // Each bar is followed by a space and details bodies are all spaces;
+    // the color codes around the bars take no columns.
+    let quote_width = quote_prefix.matches(glyphs().quote_bar).count()
+        + quote_prefix.chars().filter(|&c| c == ' ').count();
+    let lines: Vec<&str> = text.lines().collect();
+    for (i, line) in lines.iter().enumerate() {
+        if *at_line_start {
+            write!(out, "{}", quote_prefix).unwrap();
+            if i > 0 {
+                write!(out, "{}", current_indent).unwrap();
+            }
+        }
+
+        // Wrap the line if it's too long
+        let effective_width = wrap_width.saturating_sub(current_indent.len() + quote_width);
+        let wrapped_lines = wrap_line(line, effective_width, break_long);
+
+        for (j, wrapped_line) in wrapped_lines.iter().enumerate() {
+            if j > 0 {
+                write!(out, "\n{}{}", quote_prefix, current_indent).unwrap();
+            }
+            write!(out, "{}", wrapped_line).unwrap();
+        }
+
+        if i < lines.len() - 1 {
+            writeln!(out).unwrap();
+            *at_line_start = true;
+        } else {
+            *at_line_start = false;
+        }
+    }
+}
+
+fn is_known_language(assets: &HighlightingAssets, language: &str) -> bool {
+    let Ok(syntaxes) = assets.get_syntaxes() else {
+        return false;
+    };
+    syntaxes
+        .iter()
+        .any(|syntax| names_language(&syntax.name, &syntax.file_extensions, language))
+}
+
+/// Whether a syntax with this name and these extensions is the one a code
+/// fence tagged `language` means, e.g. `Rust` for `rust` or `rs`.
+fn names_language(name: &str, extensions: &[String], language: &str) -> bool {
+    name.eq_ignore_ascii_case(language)
+        || extensions
+            .iter()
+            .any(|ext| ext.eq_ignore_ascii_case(language))
+}
+
+/// Drops the blank lines before the first line with text and the whitespace
+/// after the last, keeping the indentation of that first line and every
+/// blank line in between.
+fn trim_blank_lines(text: &str) -> &str {
+    trim_leading_blank_lines(text).trim_end()
+}
+
+/// Drops the blank lines before the first line with text, keeping its indentation.
+pub fn trim_leading_blank_lines(text: &str) -> &str {
+    match text.find(|c: char| !c.is_whitespace()) {
+        Some(first) => &text[text[..first].rfind('\n').map_or(0, |newline| newline + 1)..],
+        None => "",
+    }
+}
+
+/// Zero-width characters that bracket text to underline once it is rendered.
+/// Being invisible and taking no columns, they pass through markdown, wrapping
+/// and highlighting unchanged wherever they sit between words.
+const MARK_START: char = '\u{2060}';
+const MARK_END: char = '\u{2063}';
+
+/// Brackets the byte `spans` of `text` for `underline_marks`. The spans must
+/// be sorted, apart, and on character boundaries.
+pub fn mark_spans(text: &str, spans: &[Range<usize>]) -> String {
+    let mut marked = String::with_capacity(text.len() + spans.len() * 6);
+    let mut end = 0;
+    for span in spans {
+        marked.push_str(&text[end..span.start]);
+        marked.push(MARK_START);
+        marked.push_str(&text[span.clone()]);
+        marked.push(MARK_END);
+        end = span.end;
+    }
+    marked.push_str(&text[end..]);
+    marked
+}
+
+/// Underlines what `mark_spans` bracketed in rendered output, or just drops
+/// the marks when colors are off.
+pub fn underline_marks(rendered: &str) -> String {
+    let t = theme();
+    rendered
+        .replace(MARK_START, t.underline)
+        .replace(MARK_END, t.underline_off)
+}
+
+/// The usual file extension for code in `language`, e.g. `py` for `python`,
+/// as bat's syntax definitions list it.
+pub fn language_extension(language: &str) -> Option<String> {
+    let assets = HighlightingAssets::from_binary();
+    let syntaxes = assets.get_syntaxes().ok()?;
+    syntaxes
+        .iter()
+        .find(|syntax| names_language(&syntax.name, &syntax.file_extensions, language))?
+        .file_extensions
+        .first()
+        .cloned()
+}
+
+/// Checks `name` against bat's bundled themes.
+pub fn validate_theme(name: &str) -> Result<(), String> {
+    let printer = PrettyPrinter::new();
+    if printer.themes().any(|theme| theme == name) {
+        return Ok(());
+    }
+    let available: Vec<&str> = printer.themes().collect();
+    Err(format!(
+        "Unknown theme '{}'. Available themes: {}",
+        name,
+        available.join(", ")
+    ))
+}
+
+/// Highlights a code block with bat, in a grid with line numbers unless it
+/// is `bare`.
+fn render_code_block(
+    code: &str,
+    language: &str,
+    options: &RenderOptions,
+    bare: bool,
+    out: &mut String,
+) {
+    let t = theme();
+    if code.trim().is_empty() {
+        return;
+    }
+    if language == "diff" || language == "patch" {
+        return render_diff(code, out);
+    }
+
+    let is_mermaid = language == "mermaid";
+
+    let assets = HighlightingAssets::from_binary();
+    // bat refuses unknown languages outright, so highlight those as plain text.
+    let language = if is_known_language(&assets, language) {
+        language
+    } else {
+        "txt"
+    };
+    // With line-numbers on, the response's own numbers replace bat's. The
+    // grid is drawn with box characters, so ASCII mode goes without.
+    let mut components = Vec::new();
+    if !ASCII_ENABLED.load(Ordering::Relaxed) && !bare {
+        components.push(StyleComponent::Grid);
+    }
+    if !options.line_numbers && !bare {
+        components.push(StyleComponent::LineNumbers);
+    }
+    let config = BatConfig {
+        language: Some(language),
+        colored_output: color_enabled(),
+        true_color: true,
+        term_width: term_size::dimensions().map_or(DEFAULT_TERMINAL_COLUMNS, |(cols, _)| cols),
+        style_components: StyleComponents::new(&components),
+        theme: options.theme.clone().unwrap_or_default(),
+        ..BatConfig::default()
+    };
+    let input = bat::input::Input::from_reader(Box::new(code.trim_end().as_bytes()));
+
+    // Try to use bat for syntax highlighting, fallback to simple display
+    let mut highlighted = String::new();
+    match Controller::new(&config, &assets).run(vec![input], Some(&mut highlighted)) {
+        Ok(true) => out.push_str(&highlighted),
+        _ if bare => out.push_str(code),
+        _ => render_plain_code_block(code, config.term_width, out),
+    }
+    if !out.ends_with('\n') {
+        out.push('\n');
+    }
+    if is_mermaid && options.mermaid_link {
+        writeln!(
+            out,
+            "{}Open the diagram: {}{}",
+            t.dim,
+            mermaid_live_url(code),
+            t.reset
+        )
+        .unwrap();
+    }
+}
+
+/// The language and code of a response that is one fenced code block and
+/// nothing else.
+fn only_code_block<'a>(events: &'a [Event]) -> Option<(&'a str, String)> {
+    let [Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(language))), body @ .., Event::End(TagEnd::CodeBlock)] =
+        events
+    else {
+        return None;
+    };
+    let mut code = String::new();
+    for event in body {
+        match event {
+            Event::Text(text) => code.push_str(text),
+            _ => return None,
+        }
+    }
+    Some((language, code))
+}
+
+/// Draws a code block without highlighting, for when bat fails. Lines too
+/// long for the terminal are wrapped inside the box, each continuation
+/// marked, so the terminal's own wrapping never breaks the border.
+fn render_plain_code_block(code: &str, term_width: usize, out: &mut String) {
+    let t = theme();
+    let g = glyphs();
+    let continuation = format!("{} ", g.continuation);
+    // Room after the bar and its space, less the marker on continued lines.
+    let width = term_width.saturating_sub(2 + display_width(&continuation));
+    writeln!(out, "{}{}{}", t.dim, g.box_top, g.rule.repeat(50)).unwrap();
+    for line in code.lines() {
+        for (i, piece) in wrap_line(line, width, true).into_iter().enumerate() {
+            let marker = if i == 0 { "" } else { &continuation };
+            writeln!(
+                out,
+                "{}{}{} {}{}{}{}",
+                t.dim, g.quote_bar, t.reset, t.dim, marker, t.reset, piece
+            )
+            .unwrap();
+        }
+    }
+    writeln!(
+        out,
+        "{}{}{}{}",
+        t.dim,
+        g.box_bottom,
+        g.rule.repeat(50),
+        t.reset
+    )
+    .unwrap();
+}
+
+/// A Mermaid Live Editor link with `code` loaded, carried in the URL fragment
+/// so the diagram never reaches a server.
+fn mermaid_live_url(code: &str) -> String {
+    use base64::Engine as _;
+    let state = serde_json::json!({
+        "code": code.trim_end(),
+        "mermaid": "{\"theme\": \"default\"}",
+        "autoSync": true,
+        "updateDiagram": true,
+    });
+    let encoded = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(state.to_string());
+    format!("{}{}", MERMAID_LIVE_URL, encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn math_to_unicode_converts_simple_latex() {
+        assert_eq!(math_to_unicode("\\alpha^2 + x_i"), "α² + xᵢ");
+        assert_eq!(math_to_unicode("e^{-1} \\leq \\infty"), "e⁻¹ ≤ ∞");
+        assert_eq!(
+            math_to_unicode("x^{ab} \\frac{1}{2}"),
+            "x^{ab} \\frac{1}{2}"
+        );
+    }
+
+    #[test]
+    fn normalize_math_delimiters_skips_code() {
+        assert_eq!(
+            normalize_math_delimiters("So \\(x^2\\) but `\\(y\\)`\n"),
+            "So $x^2$ but `\\(y\\)`\n"
+        );
+    }
+
+    #[test]
+    fn html_pieces_keeps_details_tags_and_text() {
+        assert_eq!(
+            html_pieces("<details open><summary>More</summary>\n"),
+            [
+                HtmlPiece::DetailsStart,
+                HtmlPiece::SummaryStart,
+                HtmlPiece::Text("More"),
+                HtmlPiece::SummaryEnd,
+                HtmlPiece::Text("\n"),
+            ]
+        );
+        assert_eq!(html_pieces("<br/>"), []);
+        assert_eq!(html_pieces("</DETAILS>"), [HtmlPiece::DetailsEnd]);
+        assert_eq!(
+            html_pieces("<kbd>Esc</kbd>"),
+            [
+                HtmlPiece::KbdStart,
+                HtmlPiece::Text("Esc"),
+                HtmlPiece::KbdEnd
+            ]
+        );
+    }
+
+    #[test]
+    fn fence_unfenced_diffs_wraps_only_the_diff() {
+        let text =
+            "Apply this:\n\n@@ -1,2 +1,2 @@\n fn main() {\n-    old();\n+    new();\n\nDone.\n";
+        assert_eq!(
+            fence_unfenced_diffs(text),
+            "Apply this:\n\n```diff\n@@ -1,2 +1,2 @@\n fn main() {\n-    old();\n+    new();\n```\n\nDone.\n"
+        );
+        let fenced = "```\ndiff --git a/x b/x\n```\n";
+        assert_eq!(fence_unfenced_diffs(fenced), fenced);
+    }
+
+    #[test]
+    fn fence_unfenced_diffs_stops_where_the_hunk_ends() {
+        let text =
+            "@@ -1,2 +1,2 @@\n fn main() {\n-    old();\n+    new();\n- item one\n- item two\n";
+        assert_eq!(
+            fence_unfenced_diffs(text),
+            "```diff\n@@ -1,2 +1,2 @@\n fn main() {\n-    old();\n+    new();\n```\n- item one\n- item two\n"
+        );
+        assert_eq!(hunk_counts("@@ -3 +3,0 @@ fn f()"), Some((1, 0)));
+    }
+
+    #[test]
+    fn emoji_sequences_are_measured_as_one_glyph() {
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let flag = "\u{1F1EF}\u{1F1F5}";
+        let thumbs_up = "\u{1F44D}\u{1F3FD}";
+        assert_eq!(display_width(family), 2);
+        assert_eq!(display_width(flag), 2);
+        assert_eq!(display_width(thumbs_up), 2);
+        assert_eq!(display_width("e\u{301}te\u{301}"), 3);
+
+        let line = format!("{} {} {} ok", family, flag, thumbs_up);
+        assert_eq!(
+            wrap_line(&line, 8, true),
+            [
+                format!("{} {} {}", family, flag, thumbs_up),
+                "ok".to_string()
+            ]
+        );
+        // A word too long for the line is split between clusters, never inside one.
+        let families = family.repeat(3);
+        assert_eq!(
+            wrap_line(&families, 5, true),
+            [family.repeat(2), family.to_string()]
+        );
+    }
+
+    #[test]
+    fn wrap_line_keeps_spaces_at_the_ends() {
+        assert_eq!(wrap_line("Hello ", 80, false), ["Hello "]);
+        assert_eq!(wrap_line(" and more", 80, false), [" and more"]);
+        assert_eq!(wrap_line("one two  three", 7, false), ["one two", "three"]);
+        assert_eq!(wrap_line("", 10, false), [""]);
+        assert_eq!(
+            wrap_line("see https://example.com/a/long/path here", 12, false),
+            ["see", "https://example.com/a/long/path", "here"]
+        );
+        assert_eq!(
+            wrap_line("see https://example.com/a/long/path here", 12, true),
+            ["see", "https://exam", "ple.com/a/lo", "ng/path here"]
+        );
+    }
+
+    #[test]
+    fn mermaid_links_carry_the_diagram_in_the_fragment() {
+        use base64::Engine as _;
+        let url = mermaid_live_url("graph TD\n  A-->B\n");
+        let encoded = url.strip_prefix(MERMAID_LIVE_URL).unwrap();
+        let state = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(encoded)
+            .unwrap();
+        let state: serde_json::Value = serde_json::from_slice(&state).unwrap();
+        assert_eq!(state["code"], "graph TD\n  A-->B");
+    }
+
+    #[test]
+    fn fence_json_pretty_prints_json_paragraphs() {
+        assert_eq!(
+            fence_json("{\"b\": 1, \"a\": [true]}"),
+            "```json\n{\n  \"b\": 1,\n  \"a\": [\n    true\n  ]\n}\n```\n"
+        );
+        assert_eq!(
+            fence_json("Here:\n\n[1, 2]\n\nDone [x]."),
+            "Here:\n\n```json\n[\n  1,\n  2\n]\n```\n\nDone [x]."
+        );
+        for text in ["{}", "[see above]", "```\n{\"a\": 1}\n```\n"] {
+            assert!(matches!(fence_json(text), Cow::Borrowed(_)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn reference_links_resolve_and_dangling_ones_degrade_to_text() {
+        let text = "See [the docs][docs], [the spec][] and [missing][nope], or [empty]().\n\n\
+                    [docs]: https://example.com/docs\n[the spec]: https://example.com/spec\n";
+        let rendered = render_markdown(text, &RenderOptions::default());
+        assert!(rendered.contains("https://example.com/docs"));
+        assert!(rendered.contains("https://example.com/spec"));
+        assert!(rendered.contains("missing"));
+        assert!(rendered.contains("empty"));
+        assert!(!rendered.contains("nope"));
+        assert!(!rendered.contains("]["));
+        assert!(!rendered.contains("()"));
+        assert!(!rendered.contains("[docs]:"));
+    }
+
+    #[test]
+    fn detect_content_tells_tables_json_and_code_from_markdown() {
+        let csv = "name,quote\nAda,\"Hello, world\"\nGrace,\"Say \"\"hi\"\"\"\n";
+        assert_eq!(
+            detect_content(csv),
+            ContentKind::Table(vec![
+                vec!["name".to_string(), "quote".to_string()],
+                vec!["Ada".to_string(), "Hello, world".to_string()],
+                vec!["Grace".to_string(), "Say \"hi\"".to_string()],
+            ])
+        );
+        assert!(matches!(
+            detect_content("a\tb\n1\t2\n"),
+            ContentKind::Table(_)
+        ));
+        assert!(matches!(detect_content("{\"a\": 1}"), ContentKind::Json(_)));
+        assert_eq!(
+            detect_content("#!/usr/bin/env python3\nprint('hi')\n"),
+            ContentKind::Code("py".to_string())
+        );
+        for markdown in [
+            "Well, yes.\nAnd, no.\n",
+            "One line, with a comma",
+            "a,b\n1,2,3\n",
+            "# Title\n\nSome *text*.",
+        ] {
+            assert_eq!(
+                detect_content(markdown),
+                ContentKind::Markdown,
+                "{}",
+                markdown
+            );
+        }
+        assert_eq!(
+            markdown_table(&[vec!["a".to_string(), "b|c".to_string()]]),
+            "| a | b\\|c |\n| --- | --- |\n"
+        );
+    }
+
+    #[test]
+    fn table_rows_collects_cells_as_text() {
+        let events: Vec<Event> = Parser::new_ext(
+            "| a | b |\n|---|---|\n| 1 | `two` |\n\nafter\n",
+            ParserOptions::all(),
+        )
+        .collect();
+        let (rows, used) = table_rows(&events[1..]);
+        assert_eq!(rows, [["a", "b"], ["1", "two"]]);
+        assert!(matches!(events[used], Event::End(TagEnd::Table)));
+    }
+
+    #[test]
+    fn table_columns_follow_the_delimiter_row_alignment() {
+        let events: Vec<Event> = Parser::new_ext(
+            "| item | qty | st |\n|:---|---:|:---:|\n| apples | 3 | ok |\n| kiwi | 120 | done |\n",
+            ParserOptions::all(),
+        )
+        .collect();
+        let Event::Start(Tag::Table(alignments)) = &events[0] else {
+            panic!("expected a table, got {:?}", events[0]);
+        };
+        let (rows, _) = table_rows(&events[1..]);
+        let mut out = String::new();
+        render_table(&rows, alignments, &mut out);
+        let plain = regex::Regex::new("\x1b\\[[0-9;]*m")
+            .unwrap()
+            .replace_all(&out, "")
+            .replace(glyphs().quote_bar, "|");
+        let lines: Vec<&str> = plain.lines().collect();
+        assert_eq!(lines[0], " item   | qty |  st  ");
+        assert_eq!(lines[2], " apples |   3 |  ok  ");
+        assert_eq!(lines[3], " kiwi   | 120 | done ");
+    }
+
+    #[test]
+    fn marked_spans_survive_rendering_as_underlines() {
+        let t = theme();
+        let text = "The answer is **probably** 42.";
+        let marked = mark_spans(text, &[16..19, 27..29]);
+        let rendered = underline_marks(&render_markdown(&marked, &RenderOptions::default()));
+        assert!(rendered.contains(&format!("{}pro{}bably", t.underline, t.underline_off)));
+        assert!(rendered.contains(&format!("{}42{}.", t.underline, t.underline_off)));
+        assert!(!rendered.contains(MARK_START) && !rendered.contains(MARK_END));
+    }
+
+    #[test]
+    fn trim_drops_only_the_outer_blank_lines() {
+        let text = "\n  \n\n    indented\n\n\nlast\n\n \n";
+        assert_eq!(trim_blank_lines(text), "    indented\n\n\nlast");
+        assert_eq!(trim_blank_lines(" \n\n"), "");
+        let raw = |trim| {
+            let options = RenderOptions {
+                render: RenderMode::Raw,
+                trim,
+                ..RenderOptions::default()
+            };
+            render_markdown("\n\nHello\n\nWorld\n\n", &options)
+        };
+        assert_eq!(raw(true), "Hello\n\nWorld\n");
+        assert_eq!(raw(false), "\n\nHello\n\nWorld\n\n");
+    }
+
+    #[test]
+    fn only_code_block_needs_the_whole_response_to_be_one_block() {
+        let events = |text| Parser::new_ext(text, ParserOptions::all()).collect::<Vec<_>>();
+        assert_eq!(
+            only_code_block(&events("```rust\nfn main() {}\n```\n")),
+            Some(("rust", "fn main() {}\n".to_string()))
+        );
+        assert_eq!(
+            only_code_block(&events("Here:\n\n```rust\nfn main() {}\n```\n")),
+            None
+        );
+        assert_eq!(
+            only_code_block(&events("```sh\nls\n```\n\n```sh\npwd\n```\n")),
+            None
+        );
+        assert_eq!(only_code_block(&events("    indented\n")), None);
+    }
+
+    #[test]
+    fn language_extension_comes_from_the_syntax_definitions() {
+        assert_eq!(language_extension("rust").as_deref(), Some("rs"));
+        assert_eq!(language_extension("Python").as_deref(), Some("py"));
+        assert_eq!(language_extension("no-such-language"), None);
+    }
+
+    #[test]
+    fn plain_code_blocks_wrap_long_lines_inside_the_box() {
+        let mut out = String::new();
+        let code = "short\nlet value = some_function(first_argument, second_argument);\n";
+        render_plain_code_block(code, 24, &mut out);
+        let ansi = regex::Regex::new("\x1b\\[[0-9;]*m").unwrap();
+        let plain = ansi.replace_all(&out, "");
+        let lines: Vec<&str> = plain.lines().collect();
+        let g = glyphs();
+        assert_eq!(lines[1], format!("{} short", g.quote_bar));
+        assert!(lines.len() > 4);
+        for line in &lines[2..lines.len() - 1] {
+            assert!(display_width(line) <= 24, "{:?}", line);
+            assert!(line.starts_with(g.quote_bar));
+        }
+        assert!(lines[3].starts_with(&format!("{} {} ", g.quote_bar, g.continuation)));
+    }
+
+    #[test]
+    fn outer_styles_survive_inner_ones_closing() {
+        let t = theme();
+        let render = |text| render_markdown(text, &RenderOptions::default());
+        let strong = format!("{}{}", t.bold, t.yellow);
+
+        let rendered = render("**bold *italic* still bold**");
+        assert!(rendered.contains(&format!("{}{} still bold{}", t.reset, strong, t.reset)));
+
+        let rendered = render("***bold italic*** plain");
+        assert!(rendered.ends_with(&format!("{} plain\n", t.reset)));
+
+        let rendered = render("**bold with `code` inside**");
+        assert!(rendered.contains(&format!("{}{} inside", t.reset, strong)));
+
+        let rendered = render("*see [docs](https://example.com) here*");
+        assert!(rendered.contains(&format!("{}{}) here", t.reset, t.italic)));
+
+        let rendered = render("# Title with *emphasis* after");
+        assert!(rendered.contains(&format!("{}{}{} after", t.reset, t.bold, t.heading)));
+    }
+
+    #[test]
+    fn ordered_list_starting_at_nine_is_sized_for_two_digits() {
+        let events: Vec<Event> = Parser::new("9. nine\n10. ten\n").collect();
+        assert!(matches!(events[0], Event::Start(Tag::List(Some(9)))));
+        let width = ordered_number_width(9, list_item_count(&events, 0));
+        assert_eq!(width, 2);
+        assert_eq!(format!("{:>width$}.", 9), " 9.");
+        assert_eq!(format!("{:>width$}.", 10), "10.");
+
+        let events: Vec<Event> = Parser::new("98. a\n99. b\n100. c\n").collect();
+        assert_eq!(ordered_number_width(98, list_item_count(&events, 0)), 3);
+    }
+
+    #[test]
+    fn truncate_display_cuts_at_word_boundaries() {
+        assert_eq!(truncate_display("short", 10), "short");
+        assert_eq!(truncate_display("exactly ten", 11), "exactly ten");
+        assert_eq!(truncate_display("hello wonderful world", 12), "hello…");
+        assert_eq!(truncate_display("hello world again", 12), "hello world…");
+        assert_eq!(truncate_display("abcdefghijklmnop", 8), "abcdefg…");
+    }
+
+    #[test]
+    fn truncate_display_counts_columns_not_bytes() {
+        // Each of these characters is two columns wide and three bytes long.
+        assert_eq!(truncate_display("日本語のテキスト", 7), "日本語…");
+        assert_eq!(truncate_display("héllo wörld", 8), "héllo…");
+        assert_eq!(truncate_display("日本語", 6), "日本語");
+    }
+
+    #[test]
+    fn wrap_width_falls_back_for_zero_columns() {
+        assert_eq!(wrap_width_for(0), wrap_width_for(DEFAULT_TERMINAL_COLUMNS));
+        assert_eq!(wrap_width_for(5), wrap_width_for(DEFAULT_TERMINAL_COLUMNS));
+        assert_eq!(wrap_width_for(DEFAULT_TERMINAL_COLUMNS), 60);
+    }
+
+    #[test]
+    fn wrap_width_is_capped_on_wide_terminals() {
+        assert_eq!(wrap_width_for(MIN_TERMINAL_COLUMNS), 15);
+        assert_eq!(wrap_width_for(120), 90);
+        assert_eq!(wrap_width_for(300), 100);
+    }
+}